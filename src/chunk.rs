@@ -1,42 +1,206 @@
+use crate::span::Span;
 use crate::value::{init_value_array, write_value_array, Value, ValueArray};
 use std::convert::From;
+use std::rc::Rc;
 
-#[derive(Debug)]
+// Largest constant index that fits in the 24-bit operand of the long
+// instructions.
+pub const CONSTANT_LONG_MAX: usize = 0xff_ffff;
+
+#[derive(Debug, Clone, Copy)]
 #[allow(non_camel_case_types)]
 pub enum OpCode {
     OP_CONSTANT,
+    OP_CONSTANT_LONG,
+    OP_NIL,
+    OP_TRUE,
+    OP_FALSE,
+    OP_POP,
+    OP_GET_LOCAL,
+    OP_SET_LOCAL,
+    OP_GET_GLOBAL,
+    OP_GET_GLOBAL_LONG,
+    OP_DEFINE_GLOBAL,
+    OP_DEFINE_GLOBAL_LONG,
+    OP_SET_GLOBAL,
+    OP_SET_GLOBAL_LONG,
+    OP_GET_UPVALUE,
+    OP_SET_UPVALUE,
+    OP_GET_PROPERTY,
+    OP_GET_PROPERTY_LONG,
+    OP_SET_PROPERTY,
+    OP_SET_PROPERTY_LONG,
+    OP_GET_SUPER,
+    OP_GET_SUPER_LONG,
+    OP_EQUAL,
+    OP_GREATER,
+    OP_LESS,
     OP_ADD,
     OP_SUBSTRACT,
     OP_MULTIPLY,
     OP_DIVIDE,
+    OP_NOT,
     OP_NEGATE,
+    OP_PRINT,
+    OP_JUMP,
+    OP_JUMP_IF_FALSE,
+    OP_LOOP,
+    OP_CALL,
+    OP_INVOKE,
+    OP_INVOKE_LONG,
+    OP_SUPER_INVOKE,
+    OP_SUPER_INVOKE_LONG,
+    OP_CLOSURE,
+    OP_CLOSURE_LONG,
+    OP_CLOSE_UPVALUE,
     OP_RETURN,
+    OP_CLASS,
+    OP_CLASS_LONG,
+    OP_INHERIT,
+    OP_METHOD,
+    OP_METHOD_LONG,
 }
 
-impl From<u8> for OpCode {
-    fn from(byte: u8) -> Self {
+impl OpCode {
+    // Bytes read from a file may not be an opcode at all, the error is the
+    // byte itself.
+    pub fn from_byte(byte: u8) -> Result<OpCode, u8> {
         match byte {
-            byte if byte == OpCode::OP_CONSTANT as u8 => OpCode::OP_CONSTANT,
-            byte if byte == OpCode::OP_ADD as u8 => OpCode::OP_ADD,
-            byte if byte == OpCode::OP_SUBSTRACT as u8 => OpCode::OP_SUBSTRACT,
-            byte if byte == OpCode::OP_MULTIPLY as u8 => OpCode::OP_MULTIPLY,
-            byte if byte == OpCode::OP_DIVIDE as u8 => OpCode::OP_DIVIDE,
-            byte if byte == OpCode::OP_NEGATE as u8 => OpCode::OP_NEGATE,
-            byte if byte == OpCode::OP_RETURN as u8 => OpCode::OP_RETURN,
-            _ => panic!("Unkown opcode!"),
+            byte if byte == OpCode::OP_CONSTANT as u8 => Ok(OpCode::OP_CONSTANT),
+            byte if byte == OpCode::OP_CONSTANT_LONG as u8 => Ok(OpCode::OP_CONSTANT_LONG),
+            byte if byte == OpCode::OP_NIL as u8 => Ok(OpCode::OP_NIL),
+            byte if byte == OpCode::OP_TRUE as u8 => Ok(OpCode::OP_TRUE),
+            byte if byte == OpCode::OP_FALSE as u8 => Ok(OpCode::OP_FALSE),
+            byte if byte == OpCode::OP_POP as u8 => Ok(OpCode::OP_POP),
+            byte if byte == OpCode::OP_GET_LOCAL as u8 => Ok(OpCode::OP_GET_LOCAL),
+            byte if byte == OpCode::OP_SET_LOCAL as u8 => Ok(OpCode::OP_SET_LOCAL),
+            byte if byte == OpCode::OP_GET_GLOBAL as u8 => Ok(OpCode::OP_GET_GLOBAL),
+            byte if byte == OpCode::OP_GET_GLOBAL_LONG as u8 => Ok(OpCode::OP_GET_GLOBAL_LONG),
+            byte if byte == OpCode::OP_DEFINE_GLOBAL as u8 => Ok(OpCode::OP_DEFINE_GLOBAL),
+            byte if byte == OpCode::OP_DEFINE_GLOBAL_LONG as u8 => {
+                Ok(OpCode::OP_DEFINE_GLOBAL_LONG)
+            }
+            byte if byte == OpCode::OP_SET_GLOBAL as u8 => Ok(OpCode::OP_SET_GLOBAL),
+            byte if byte == OpCode::OP_SET_GLOBAL_LONG as u8 => Ok(OpCode::OP_SET_GLOBAL_LONG),
+            byte if byte == OpCode::OP_GET_UPVALUE as u8 => Ok(OpCode::OP_GET_UPVALUE),
+            byte if byte == OpCode::OP_SET_UPVALUE as u8 => Ok(OpCode::OP_SET_UPVALUE),
+            byte if byte == OpCode::OP_GET_PROPERTY as u8 => Ok(OpCode::OP_GET_PROPERTY),
+            byte if byte == OpCode::OP_GET_PROPERTY_LONG as u8 => Ok(OpCode::OP_GET_PROPERTY_LONG),
+            byte if byte == OpCode::OP_SET_PROPERTY as u8 => Ok(OpCode::OP_SET_PROPERTY),
+            byte if byte == OpCode::OP_SET_PROPERTY_LONG as u8 => Ok(OpCode::OP_SET_PROPERTY_LONG),
+            byte if byte == OpCode::OP_GET_SUPER as u8 => Ok(OpCode::OP_GET_SUPER),
+            byte if byte == OpCode::OP_GET_SUPER_LONG as u8 => Ok(OpCode::OP_GET_SUPER_LONG),
+            byte if byte == OpCode::OP_EQUAL as u8 => Ok(OpCode::OP_EQUAL),
+            byte if byte == OpCode::OP_GREATER as u8 => Ok(OpCode::OP_GREATER),
+            byte if byte == OpCode::OP_LESS as u8 => Ok(OpCode::OP_LESS),
+            byte if byte == OpCode::OP_ADD as u8 => Ok(OpCode::OP_ADD),
+            byte if byte == OpCode::OP_SUBSTRACT as u8 => Ok(OpCode::OP_SUBSTRACT),
+            byte if byte == OpCode::OP_MULTIPLY as u8 => Ok(OpCode::OP_MULTIPLY),
+            byte if byte == OpCode::OP_DIVIDE as u8 => Ok(OpCode::OP_DIVIDE),
+            byte if byte == OpCode::OP_NOT as u8 => Ok(OpCode::OP_NOT),
+            byte if byte == OpCode::OP_NEGATE as u8 => Ok(OpCode::OP_NEGATE),
+            byte if byte == OpCode::OP_PRINT as u8 => Ok(OpCode::OP_PRINT),
+            byte if byte == OpCode::OP_JUMP as u8 => Ok(OpCode::OP_JUMP),
+            byte if byte == OpCode::OP_JUMP_IF_FALSE as u8 => Ok(OpCode::OP_JUMP_IF_FALSE),
+            byte if byte == OpCode::OP_LOOP as u8 => Ok(OpCode::OP_LOOP),
+            byte if byte == OpCode::OP_CALL as u8 => Ok(OpCode::OP_CALL),
+            byte if byte == OpCode::OP_INVOKE as u8 => Ok(OpCode::OP_INVOKE),
+            byte if byte == OpCode::OP_INVOKE_LONG as u8 => Ok(OpCode::OP_INVOKE_LONG),
+            byte if byte == OpCode::OP_SUPER_INVOKE as u8 => Ok(OpCode::OP_SUPER_INVOKE),
+            byte if byte == OpCode::OP_SUPER_INVOKE_LONG as u8 => Ok(OpCode::OP_SUPER_INVOKE_LONG),
+            byte if byte == OpCode::OP_CLOSURE as u8 => Ok(OpCode::OP_CLOSURE),
+            byte if byte == OpCode::OP_CLOSURE_LONG as u8 => Ok(OpCode::OP_CLOSURE_LONG),
+            byte if byte == OpCode::OP_CLOSE_UPVALUE as u8 => Ok(OpCode::OP_CLOSE_UPVALUE),
+            byte if byte == OpCode::OP_RETURN as u8 => Ok(OpCode::OP_RETURN),
+            byte if byte == OpCode::OP_CLASS as u8 => Ok(OpCode::OP_CLASS),
+            byte if byte == OpCode::OP_CLASS_LONG as u8 => Ok(OpCode::OP_CLASS_LONG),
+            byte if byte == OpCode::OP_INHERIT as u8 => Ok(OpCode::OP_INHERIT),
+            byte if byte == OpCode::OP_METHOD as u8 => Ok(OpCode::OP_METHOD),
+            byte if byte == OpCode::OP_METHOD_LONG as u8 => Ok(OpCode::OP_METHOD_LONG),
+            _ => Err(byte),
         }
     }
+
+    // Every instruction taking a constant has a long form with a three
+    // byte operand, for constants past the first 256.
+    pub fn long_form(self) -> OpCode {
+        match self {
+            OpCode::OP_CONSTANT => OpCode::OP_CONSTANT_LONG,
+            OpCode::OP_GET_GLOBAL => OpCode::OP_GET_GLOBAL_LONG,
+            OpCode::OP_DEFINE_GLOBAL => OpCode::OP_DEFINE_GLOBAL_LONG,
+            OpCode::OP_SET_GLOBAL => OpCode::OP_SET_GLOBAL_LONG,
+            OpCode::OP_GET_PROPERTY => OpCode::OP_GET_PROPERTY_LONG,
+            OpCode::OP_SET_PROPERTY => OpCode::OP_SET_PROPERTY_LONG,
+            OpCode::OP_GET_SUPER => OpCode::OP_GET_SUPER_LONG,
+            OpCode::OP_INVOKE => OpCode::OP_INVOKE_LONG,
+            OpCode::OP_SUPER_INVOKE => OpCode::OP_SUPER_INVOKE_LONG,
+            OpCode::OP_CLOSURE => OpCode::OP_CLOSURE_LONG,
+            OpCode::OP_CLASS => OpCode::OP_CLASS_LONG,
+            OpCode::OP_METHOD => OpCode::OP_METHOD_LONG,
+            other => panic!("No long form for {:?}.", other),
+        }
+    }
+
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::OP_CONSTANT_LONG
+                | OpCode::OP_GET_GLOBAL_LONG
+                | OpCode::OP_DEFINE_GLOBAL_LONG
+                | OpCode::OP_SET_GLOBAL_LONG
+                | OpCode::OP_GET_PROPERTY_LONG
+                | OpCode::OP_SET_PROPERTY_LONG
+                | OpCode::OP_GET_SUPER_LONG
+                | OpCode::OP_INVOKE_LONG
+                | OpCode::OP_SUPER_INVOKE_LONG
+                | OpCode::OP_CLOSURE_LONG
+                | OpCode::OP_CLASS_LONG
+                | OpCode::OP_METHOD_LONG
+        )
+    }
+}
+
+impl From<u8> for OpCode {
+    fn from(byte: u8) -> Self {
+        OpCode::from_byte(byte).unwrap_or_else(|_| panic!("Unkown opcode!"))
+    }
+}
+
+// The first byte of a run of bytecode compiled from the same source line.
+#[derive(Debug)]
+struct LineStart {
+    offset: usize,
+    line: usize,
+}
+
+// The first byte of a run of bytecode compiled from the same source span.
+// There is one for almost every instruction that has a span, so it is kept
+// small.
+#[derive(Debug)]
+struct SpanStart {
+    offset: u32,
+    start: u32,
+    length: u32,
 }
 
 #[derive(Debug)]
 pub struct Chunk {
     code: Vec<u8>,
-    lines: Vec<usize>,
+    // Run-length encoded, a new entry is only added when the line changes,
+    // instead of storing the line of every byte.
+    lines: Vec<LineStart>,
+    // The code each instruction was compiled from, encoded the same way.
+    // An instruction and its operands always share a span. Only the
+    // instructions that can fail at runtime have one, the others are left
+    // in the run before them, nothing looks up their span.
+    spans: Vec<SpanStart>,
+    // The spans point into it, shared by every function compiled from the
+    // same source.
+    source: Option<Rc<str>>,
     constants: ValueArray,
 }
 
 impl Chunk {
-    #[allow(dead_code)]
     pub fn count(&self) -> usize {
         self.code.len()
     }
@@ -45,32 +209,205 @@ impl Chunk {
         OpCode::from(self.code[offset])
     }
 
+    // Operands are raw bytes, not opcodes, so they are read without
+    // decoding them.
+    pub fn get_byte(&self, offset: usize) -> u8 {
+        self.code[offset]
+    }
+
+    // Jump operands are written after the jump's target is known.
+    pub fn set_byte(&mut self, offset: usize, byte: u8) {
+        self.code[offset] = byte;
+    }
+
+    // Binary search for the last run starting at or before the offset.
     pub fn get_line(&self, offset: usize) -> usize {
-        *self
-            .lines
-            .get(offset)
-            .expect(&format!("No chunck ar offset: {}", offset))
+        if offset >= self.code.len() {
+            panic!("No chunck ar offset: {}", offset);
+        }
+
+        let run = self.lines.partition_point(|start| start.offset <= offset);
+        self.lines[run - 1].line
+    }
+
+    // Same search as get_line(), there is no span before the first
+    // instruction that can fail.
+    pub fn get_span(&self, offset: usize) -> Option<Span> {
+        if offset >= self.code.len() {
+            panic!("No chunck ar offset: {}", offset);
+        }
+
+        let run = self
+            .spans
+            .partition_point(|start| start.offset as usize <= offset);
+        run.checked_sub(1).map(|run| Span {
+            start: self.spans[run].start as usize,
+            length: self.spans[run].length as usize,
+        })
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn set_source(&mut self, source: Rc<str>) {
+        self.source = Some(source);
     }
 
-    pub fn get_constant_value(&self, offset: usize) -> f64 {
+    pub fn line_run_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn span_run_count(&self) -> usize {
+        self.spans.len()
+    }
+
+    // The offset where each run starts and its line.
+    pub fn line_runs(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.lines.iter().map(|start| (start.offset, start.line))
+    }
+
+    pub fn get_constant_value(&self, offset: usize) -> Value {
         self.constants.get(offset)
     }
+
+    pub fn constant_count(&self) -> usize {
+        self.constants.count()
+    }
 }
 
 pub fn init_chunk() -> Chunk {
     Chunk {
         code: vec![],
         lines: vec![],
+        spans: vec![],
+        source: None,
         constants: init_value_array(),
     }
 }
 
-pub fn write_chunk(chunk: &mut Chunk, byte: u8, line: usize) {
+pub fn write_chunk(chunk: &mut Chunk, byte: u8, line: usize, span: Option<Span>) {
     chunk.code.push(byte);
-    chunk.lines.push(line);
+    let offset = chunk.code.len() - 1;
+
+    // Still on the same line, the current run covers the new byte.
+    if chunk.lines.last().is_none_or(|start| start.line != line) {
+        chunk.lines.push(LineStart { offset, line });
+    }
+
+    if let Some(span) = span {
+        let (start, length) = (span.start as u32, span.length as u32);
+        if chunk
+            .spans
+            .last()
+            .is_none_or(|run| (run.start, run.length) != (start, length))
+        {
+            chunk.spans.push(SpanStart {
+                offset: offset as u32,
+                start,
+                length,
+            });
+        }
+    }
 }
 
 pub fn add_constant(chunk: &mut Chunk, value: Value) -> usize {
     write_value_array(&mut chunk.constants, value);
-    return chunk.constants.count() - 1;
+    chunk.constants.count() - 1
+}
+
+// Writes an instruction taking a constant. The first 256 constants fit in
+// a one byte operand, the rest need the long form of the instruction and
+// its three byte operand, most significant byte first.
+pub fn write_constant_instruction(
+    chunk: &mut Chunk,
+    opcode: OpCode,
+    constant: usize,
+    line: usize,
+    span: Option<Span>,
+) {
+    if constant <= u8::MAX as usize {
+        write_chunk(chunk, opcode as u8, line, span);
+        write_chunk(chunk, constant as u8, line, span);
+    } else {
+        write_chunk(chunk, opcode.long_form() as u8, line, span);
+        write_chunk(chunk, ((constant >> 16) & 0xff) as u8, line, span);
+        write_chunk(chunk, ((constant >> 8) & 0xff) as u8, line, span);
+        write_chunk(chunk, (constant & 0xff) as u8, line, span);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes the instructions of each line in turn, one byte per opcode.
+    fn chunk_with_lines(lines: &[(usize, &[OpCode])]) -> Chunk {
+        let mut chunk = init_chunk();
+        for &(line, opcodes) in lines {
+            for &opcode in opcodes {
+                write_chunk(&mut chunk, opcode as u8, line, None);
+            }
+        }
+        chunk
+    }
+
+    #[test]
+    fn first_offset_of_a_run() {
+        let chunk = chunk_with_lines(&[
+            (1, &[OpCode::OP_NIL, OpCode::OP_POP]),
+            (2, &[OpCode::OP_TRUE, OpCode::OP_POP]),
+            (4, &[OpCode::OP_FALSE]),
+        ]);
+
+        assert_eq!(chunk.line_run_count(), 3);
+        assert_eq!(chunk.get_line(0), 1);
+        assert_eq!(chunk.get_line(2), 2);
+        assert_eq!(chunk.get_line(4), 4);
+    }
+
+    #[test]
+    fn last_offset_before_the_next_run() {
+        let chunk = chunk_with_lines(&[
+            (1, &[OpCode::OP_NIL, OpCode::OP_POP]),
+            (2, &[OpCode::OP_TRUE, OpCode::OP_NOT, OpCode::OP_POP]),
+            (3, &[OpCode::OP_RETURN]),
+        ]);
+
+        assert_eq!(chunk.get_line(1), 1);
+        assert_eq!(chunk.get_line(4), 2);
+        assert_eq!(chunk.get_line(5), 3);
+    }
+
+    // A constant instruction and its operand, then more instructions on the
+    // same line, all share one run until the line changes.
+    #[test]
+    fn instructions_on_one_line_share_a_run() {
+        let mut chunk = init_chunk();
+        let constant = add_constant(&mut chunk, Value::Number(1.0));
+        write_constant_instruction(&mut chunk, OpCode::OP_CONSTANT, constant, 7, None);
+        write_chunk(&mut chunk, OpCode::OP_NEGATE as u8, 7, None);
+        write_chunk(&mut chunk, OpCode::OP_PRINT as u8, 7, None);
+        write_chunk(&mut chunk, OpCode::OP_NIL as u8, 8, None);
+        write_chunk(&mut chunk, OpCode::OP_RETURN as u8, 8, None);
+
+        assert_eq!(chunk.line_run_count(), 2);
+        for offset in 0..4 {
+            assert_eq!(chunk.get_line(offset), 7);
+        }
+        assert_eq!(chunk.get_line(4), 8);
+        assert_eq!(chunk.get_line(5), 8);
+    }
+
+    #[test]
+    fn a_line_seen_again_starts_a_new_run() {
+        let chunk = chunk_with_lines(&[
+            (1, &[OpCode::OP_NIL]),
+            (2, &[OpCode::OP_NIL]),
+            (1, &[OpCode::OP_ADD]),
+        ]);
+
+        assert_eq!(chunk.line_run_count(), 3);
+        assert_eq!(chunk.get_line(2), 1);
+    }
 }
@@ -1,31 +1,1375 @@
-use crate::scanner::{Scanner, TokenType};
-
-pub fn compile(source: &str) {
-    let mut scanner = Scanner::new(source);
-
-    let mut line: i32 = -1;
-    loop {
-        let token = scanner.scan_token();
-        if token.line != line {
-            print!("{number:>width$} ", number = token.line, width = 4);
-            line = token.line;
-        } else {
-            print!("    | ");
-        }
-        print!(
-            "{token_number:>width$} '{literal}'\n",
-            token_number = token.ttype as i32,
-            width = 2,
-            literal = scanner
-                .source
-                .chars()
-                .skip(token.start)
-                .take(token.length)
-                .collect::<String>(),
+use crate::chunk::{
+    add_constant, write_chunk, write_constant_instruction, Chunk, OpCode, CONSTANT_LONG_MAX,
+};
+#[cfg(feature = "debug-print-code")]
+use crate::debug;
+use crate::memory::{Heap, ObjRef};
+use crate::scanner::{Scanner, Token, TokenType};
+use crate::span::{print_span, Span};
+use crate::table::{init_table, Table};
+use crate::value::{Obj, ObjFunction, Value};
+use std::rc::Rc;
+
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+
+struct Local {
+    // Tokens point into the source, but some locals like the one in slot
+    // zero have no source token, so the lexeme is stored instead.
+    name: String,
+    // Scope depth of the block where the local was declared, or None while
+    // its initializer is being compiled.
+    depth: Option<usize>,
+    // True once a closure captures the local, so it is moved to the heap
+    // when it goes out of scope instead of just being popped.
+    is_captured: bool,
+}
+
+// A variable from an enclosing function used by the function being compiled.
+#[derive(Clone, Copy)]
+struct Upvalue {
+    // Either a local slot of the enclosing function or an index into its
+    // own upvalues.
+    index: u8,
+    is_local: bool,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionType {
+    TypeFunction,
+    TypeInitializer,
+    TypeMethod,
+    TypeScript,
+}
+
+// There is one compiler for each function being compiled, the innermost one
+// is the parser's current compiler and the others are reached through the
+// enclosing chain.
+struct Compiler {
+    enclosing: Option<Box<Compiler>>,
+    function: ObjFunction,
+    function_type: FunctionType,
+    // Locals in the order they were declared, which is also the order of
+    // their slots in the VM stack.
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    // The string constants of the chunk and their index, so a name or
+    // literal used again reuses its slot instead of taking a new one.
+    strings: Table,
+    // Number of blocks surrounding the code being compiled, zero is the
+    // global scope.
+    scope_depth: usize,
+}
+
+impl Compiler {
+    fn new(function_type: FunctionType, name: Option<ObjRef>, source: Rc<str>) -> Compiler {
+        let mut locals = Vec::with_capacity(UINT8_COUNT);
+        // The VM uses the first slot of every call frame for the function
+        // being called. In methods it holds the receiver, so `this`
+        // resolves to it like any other local, elsewhere the empty name
+        // keeps user code from using it.
+        let slot_zero = match function_type {
+            FunctionType::TypeFunction | FunctionType::TypeScript => String::new(),
+            FunctionType::TypeInitializer | FunctionType::TypeMethod => String::from("this"),
+        };
+        locals.push(Local {
+            name: slot_zero,
+            depth: Some(0),
+            is_captured: false,
+        });
+
+        let mut function = ObjFunction::new(name);
+        function.chunk.set_source(source);
+
+        Compiler {
+            enclosing: None,
+            function,
+            function_type,
+            locals,
+            upvalues: Vec::with_capacity(UINT8_COUNT),
+            strings: init_table(),
+            scope_depth: 0,
+        }
+    }
+}
+
+// There is one for each class being compiled, so `this` can be checked
+// against the innermost one.
+struct ClassCompiler {
+    enclosing: Option<Box<ClassCompiler>>,
+    has_superclass: bool,
+}
+
+struct Parser<'a> {
+    // Shared with the chunks, so runtime errors can show the code too.
+    source: Rc<str>,
+    scanner: Scanner<'a>,
+    current: Token,
+    previous: Token,
+    had_error: bool,
+    // After a syntax error the parser keeps going, but any other error
+    // until it resynchronizes is probably a cascade of the first one.
+    panic_mode: bool,
+    // String literals and functions are allocated while compiling.
+    heap: &'a mut Heap,
+    // Marks the objects the VM keeps alive while the compiler runs, so a
+    // collection started by the compiler doesn't free them.
+    mark_vm_roots: &'a dyn Fn(&mut Heap),
+    compiler: Compiler,
+    class_compiler: Option<Box<ClassCompiler>>,
+    // Where the left operand of the infix expression being parsed starts,
+    // so the instruction covers the whole expression.
+    infix_start: Span,
+}
+
+// Precedence levels from lowest to highest.
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Precedence {
+    PrecNone,
+    PrecAssignment, // =
+    PrecOr,         // or
+    PrecAnd,        // and
+    PrecEquality,   // == !=
+    PrecComparison, // < > <= >=
+    PrecTerm,       // + -
+    PrecFactor,     // * /
+    PrecUnary,      // ! -
+    PrecCall,       // . ()
+    PrecPrimary,
+}
+
+impl Precedence {
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::PrecNone => Precedence::PrecAssignment,
+            Precedence::PrecAssignment => Precedence::PrecOr,
+            Precedence::PrecOr => Precedence::PrecAnd,
+            Precedence::PrecAnd => Precedence::PrecEquality,
+            Precedence::PrecEquality => Precedence::PrecComparison,
+            Precedence::PrecComparison => Precedence::PrecTerm,
+            Precedence::PrecTerm => Precedence::PrecFactor,
+            Precedence::PrecFactor => Precedence::PrecUnary,
+            Precedence::PrecUnary => Precedence::PrecCall,
+            Precedence::PrecCall | Precedence::PrecPrimary => Precedence::PrecPrimary,
+        }
+    }
+}
+
+// The flag tells the parse function if the expression can be the target
+// of an assignment.
+type ParseFn<'a> = fn(&mut Parser<'a>, bool);
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    fn new(
+        prefix: Option<ParseFn<'a>>,
+        infix: Option<ParseFn<'a>>,
+        precedence: Precedence,
+    ) -> Self {
+        ParseRule {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+// The C version keeps the rules in an array indexed by token type, a match
+// over the TokenType gives the same table without relying on the enum
+// discriminants.
+fn get_rule<'a>(ttype: TokenType) -> ParseRule<'a> {
+    use Precedence::*;
+    use TokenType::*;
+
+    match ttype {
+        TokenLeftParen => ParseRule::new(Some(Parser::grouping), Some(Parser::call), PrecCall),
+        TokenDot => ParseRule::new(None, Some(Parser::dot), PrecCall),
+        TokenMinus => ParseRule::new(Some(Parser::unary), Some(Parser::binary), PrecTerm),
+        TokenPlus => ParseRule::new(None, Some(Parser::binary), PrecTerm),
+        TokenSlash => ParseRule::new(None, Some(Parser::binary), PrecFactor),
+        TokenStar => ParseRule::new(None, Some(Parser::binary), PrecFactor),
+        TokenBang => ParseRule::new(Some(Parser::unary), None, PrecNone),
+        TokenBangEqual => ParseRule::new(None, Some(Parser::binary), PrecEquality),
+        TokenEqualEqual => ParseRule::new(None, Some(Parser::binary), PrecEquality),
+        TokenGreater => ParseRule::new(None, Some(Parser::binary), PrecComparison),
+        TokenGreaterEqual => ParseRule::new(None, Some(Parser::binary), PrecComparison),
+        TokenLess => ParseRule::new(None, Some(Parser::binary), PrecComparison),
+        TokenLessEqual => ParseRule::new(None, Some(Parser::binary), PrecComparison),
+        TokenIdentifier => ParseRule::new(Some(Parser::variable), None, PrecNone),
+        TokenString => ParseRule::new(Some(Parser::string), None, PrecNone),
+        TokenSuper => ParseRule::new(Some(Parser::super_), None, PrecNone),
+        TokenNumber => ParseRule::new(Some(Parser::number), None, PrecNone),
+        TokenFalse => ParseRule::new(Some(Parser::literal), None, PrecNone),
+        TokenAnd => ParseRule::new(None, Some(Parser::and), PrecAnd),
+        TokenNil => ParseRule::new(Some(Parser::literal), None, PrecNone),
+        TokenOr => ParseRule::new(None, Some(Parser::or), PrecOr),
+        TokenThis => ParseRule::new(Some(Parser::this), None, PrecNone),
+        TokenTrue => ParseRule::new(Some(Parser::literal), None, PrecNone),
+        _ => ParseRule::new(None, None, PrecNone),
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str, heap: &'a mut Heap, mark_vm_roots: &'a dyn Fn(&mut Heap)) -> Self {
+        // Placeholder until the first advance() reads a real token.
+        let none = Token {
+            ttype: TokenType::TokenEOF,
+            start: 0,
+            length: 0,
+            line: 0,
+            span: Span {
+                start: 0,
+                length: 0,
+            },
+            message: None,
+        };
+        let infix_start = none.span;
+
+        let source_rc: Rc<str> = Rc::from(source);
+
+        Parser {
+            source: source_rc.clone(),
+            scanner: Scanner::new(source),
+            current: none.clone(),
+            previous: none,
+            had_error: false,
+            panic_mode: false,
+            heap,
+            mark_vm_roots,
+            compiler: Compiler::new(FunctionType::TypeScript, None, source_rc),
+            class_compiler: None,
+            infix_start,
+        }
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler.function.chunk
+    }
+
+    fn advance(&mut self) {
+        // Every object allocated so far is reachable from the functions
+        // being compiled when the next token is read.
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+
+        self.previous = self.current.clone();
+
+        loop {
+            self.current = self.scanner.scan_token();
+            if self.current.ttype != TokenType::TokenError {
+                break;
+            }
+
+            let message = self.current.message.clone().unwrap_or_default();
+            self.error_at_current(&message);
+        }
+    }
+
+    fn consume(&mut self, ttype: TokenType, message: &str) {
+        if self.current.ttype == ttype {
+            self.advance();
+            return;
+        }
+
+        self.error_at_current(message);
+    }
+
+    fn check(&self, ttype: TokenType) -> bool {
+        self.current.ttype == ttype
+    }
+
+    fn match_token(&mut self, ttype: TokenType) -> bool {
+        if !self.check(ttype) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn emit_byte(&mut self, byte: u8) {
+        let line = self.previous.line as usize;
+        write_chunk(self.current_chunk(), byte, line, None);
+    }
+
+    fn emit_bytes(&mut self, byte1: u8, byte2: u8) {
+        self.emit_byte(byte1);
+        self.emit_byte(byte2);
+    }
+
+    // Only the instructions that can fail at runtime keep their span, so
+    // the error can point at all the code they were compiled from.
+    fn emit_byte_at(&mut self, byte: u8, span: Span) {
+        let line = self.previous.line as usize;
+        write_chunk(self.current_chunk(), byte, line, Some(span));
+    }
+
+    fn emit_bytes_at(&mut self, byte1: u8, byte2: u8, span: Span) {
+        self.emit_byte_at(byte1, span);
+        self.emit_byte_at(byte2, span);
+    }
+
+    // From the given start to the end of the last token.
+    fn span_from(&self, start: Span) -> Span {
+        start.to(self.previous.span)
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::OP_LOOP as u8);
+
+        // Jump over the operand too.
+        let offset = self.current_chunk().count() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+
+        self.emit_byte(((offset >> 8) & 0xff) as u8);
+        self.emit_byte((offset & 0xff) as u8);
+    }
+
+    // Emits a jump with a placeholder operand, returns the offset of the
+    // operand so it can be patched once the target is known.
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_byte(0xff);
+        self.emit_byte(0xff);
+        self.current_chunk().count() - 2
+    }
+
+    // Functions without a return statement implicitly return nil.
+    fn emit_return(&mut self) {
+        // An initializer always returns the instance, found in slot zero.
+        if self.compiler.function_type == FunctionType::TypeInitializer {
+            self.emit_bytes(OpCode::OP_GET_LOCAL as u8, 0);
+        } else {
+            self.emit_byte(OpCode::OP_NIL as u8);
+        }
+        self.emit_byte(OpCode::OP_RETURN as u8);
+    }
+
+    fn make_constant(&mut self, value: Value) -> usize {
+        // Strings are interned, so the same string is always the same key.
+        let string = match value {
+            Value::Obj(obj) if self.heap.is_string(value) => {
+                Some((obj, self.heap.as_string(obj).hash))
+            }
+            _ => None,
+        };
+        if let Some((key, hash)) = string {
+            if let Some(existing) = self.compiler.strings.get(key, hash) {
+                return existing.as_number() as usize;
+            }
+        }
+
+        let constant = add_constant(self.current_chunk(), value);
+        if constant > CONSTANT_LONG_MAX {
+            self.error("Too many constants in one chunk.");
+            return 0;
+        }
+
+        if let Some((key, hash)) = string {
+            let index = Value::Number(constant as f64);
+            self.compiler.strings.set(key, hash, index);
+        }
+        constant
+    }
+
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.make_constant(value);
+        self.emit_constant_instruction(OpCode::OP_CONSTANT, constant);
+    }
+
+    // The instruction and its operand, in the long form when the constant
+    // doesn't fit in one byte.
+    fn emit_constant_instruction_at(&mut self, opcode: OpCode, constant: usize, span: Span) {
+        let line = self.previous.line as usize;
+        write_constant_instruction(self.current_chunk(), opcode, constant, line, Some(span));
+    }
+
+    fn emit_constant_instruction(&mut self, opcode: OpCode, constant: usize) {
+        let line = self.previous.line as usize;
+        write_constant_instruction(self.current_chunk(), opcode, constant, line, None);
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 to adjust for the bytecode for the jump offset itself.
+        let jump = self.current_chunk().count() - offset - 2;
+
+        if jump > u16::MAX as usize {
+            self.error("Too much code to jump over.");
+        }
+
+        let chunk = self.current_chunk();
+        chunk.set_byte(offset, ((jump >> 8) & 0xff) as u8);
+        chunk.set_byte(offset + 1, (jump & 0xff) as u8);
+    }
+
+    fn collect_garbage(&mut self) {
+        let compiler = &self.compiler;
+        let mark_vm_roots = self.mark_vm_roots;
+
+        self.heap.collect_garbage(|heap| {
+            let mut compiler = Some(compiler);
+            while let Some(current) = compiler {
+                heap.mark_function(&current.function);
+                compiler = current.enclosing.as_deref();
+            }
+            mark_vm_roots(heap);
+        });
+    }
+
+    fn init_compiler(&mut self, function_type: FunctionType) {
+        let lexeme = self.scanner.lexeme(&self.previous);
+        let name = self.heap.copy_string(&lexeme);
+
+        let compiler = Compiler::new(function_type, Some(name), self.source.clone());
+        let enclosing = std::mem::replace(&mut self.compiler, compiler);
+        self.compiler.enclosing = Some(Box::new(enclosing));
+    }
+
+    // Finishes the current function and makes the enclosing compiler the
+    // current one again. The upvalues tell the enclosing function which
+    // variables the new closure captures.
+    fn end_compiler(&mut self) -> (ObjFunction, Vec<Upvalue>) {
+        self.emit_return();
+        self.compiler.function.upvalue_count = self.compiler.upvalues.len();
+
+        #[cfg(feature = "debug-print-code")]
+        {
+            if !self.had_error {
+                let name = match self.compiler.function.name {
+                    Some(name) => self.heap.as_string(name).chars.clone(),
+                    None => String::from("<script>"),
+                };
+                debug::dissassemble_chunk(&self.compiler.function.chunk, &name, self.heap);
+            }
+        }
+
+        match self.compiler.enclosing.take() {
+            Some(enclosing) => {
+                let compiler = std::mem::replace(&mut self.compiler, *enclosing);
+                (compiler.function, compiler.upvalues)
+            }
+            None => (
+                std::mem::replace(&mut self.compiler.function, ObjFunction::new(None)),
+                vec![],
+            ),
+        }
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        // Remember the operator.
+        let start = self.infix_start;
+        let operator_type = self.previous.ttype;
+
+        // Compile the right operand.
+        let rule = get_rule(operator_type);
+        self.parse_precedence(rule.precedence.next());
+
+        // Emit the operator instruction.
+        // There are no instructions for !=, >= and <=, each one is the
+        // negation of another comparison.
+        let span = self.span_from(start);
+        match operator_type {
+            TokenType::TokenBangEqual => {
+                self.emit_bytes_at(OpCode::OP_EQUAL as u8, OpCode::OP_NOT as u8, span)
+            }
+            TokenType::TokenEqualEqual => self.emit_byte_at(OpCode::OP_EQUAL as u8, span),
+            TokenType::TokenGreater => self.emit_byte_at(OpCode::OP_GREATER as u8, span),
+            TokenType::TokenGreaterEqual => {
+                self.emit_bytes_at(OpCode::OP_LESS as u8, OpCode::OP_NOT as u8, span)
+            }
+            TokenType::TokenLess => self.emit_byte_at(OpCode::OP_LESS as u8, span),
+            TokenType::TokenLessEqual => {
+                self.emit_bytes_at(OpCode::OP_GREATER as u8, OpCode::OP_NOT as u8, span)
+            }
+            TokenType::TokenPlus => self.emit_byte_at(OpCode::OP_ADD as u8, span),
+            TokenType::TokenMinus => self.emit_byte_at(OpCode::OP_SUBSTRACT as u8, span),
+            TokenType::TokenStar => self.emit_byte_at(OpCode::OP_MULTIPLY as u8, span),
+            TokenType::TokenSlash => self.emit_byte_at(OpCode::OP_DIVIDE as u8, span),
+            _ => (), // Unreachable.
+        }
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let start = self.infix_start;
+        let arg_count = self.argument_list();
+        let span = self.span_from(start);
+        self.emit_bytes_at(OpCode::OP_CALL as u8, arg_count, span);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        let start = self.infix_start;
+        self.consume(
+            TokenType::TokenIdentifier,
+            "Expect property name after '.'.",
+        );
+        let name = self.previous.clone();
+        let name = self.identifier_constant(&name);
+
+        if can_assign && self.match_token(TokenType::TokenEqual) {
+            self.expression();
+            let span = self.span_from(start);
+            self.emit_constant_instruction_at(OpCode::OP_SET_PROPERTY, name, span);
+        } else if self.match_token(TokenType::TokenLeftParen) {
+            // A method call is compiled into a single instruction, so the
+            // VM doesn't need to allocate a bound method for it.
+            let arg_count = self.argument_list();
+            let span = self.span_from(start);
+            self.emit_constant_instruction_at(OpCode::OP_INVOKE, name, span);
+            self.emit_byte_at(arg_count, span);
+        } else {
+            let span = self.span_from(start);
+            self.emit_constant_instruction_at(OpCode::OP_GET_PROPERTY, name, span);
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::TokenRightParen, "Expect ')' after expression.");
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous.ttype {
+            TokenType::TokenFalse => self.emit_byte(OpCode::OP_FALSE as u8),
+            TokenType::TokenNil => self.emit_byte(OpCode::OP_NIL as u8),
+            TokenType::TokenTrue => self.emit_byte(OpCode::OP_TRUE as u8),
+            _ => (), // Unreachable.
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        let lexeme = self.scanner.lexeme(&self.previous);
+        let value: f64 = lexeme
+            .parse()
+            .unwrap_or_else(|_| panic!("Invalid number literal: {}", lexeme));
+        self.emit_constant(Value::Number(value));
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        // If the left operand is truthy skip the right one, the left
+        // value is the result.
+        let else_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+        let end_jump = self.emit_jump(OpCode::OP_JUMP);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::OP_POP as u8);
+
+        self.parse_precedence(Precedence::PrecOr);
+        self.patch_jump(end_jump);
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        // Trim the leading and trailing quotation marks.
+        let lexeme = self.scanner.lexeme(&self.previous);
+        let string = self.heap.copy_string(&lexeme[1..lexeme.len() - 1]);
+        self.emit_constant(Value::Obj(string));
+    }
+
+    // The C version makes up synthetic tokens for `this` and `super`, here
+    // the name is all that is needed.
+    fn named_variable(&mut self, name: &str, can_assign: bool) {
+        let start = self.previous.span;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(0, name) {
+            (OpCode::OP_GET_LOCAL, OpCode::OP_SET_LOCAL, slot as usize)
+        } else if let Some(index) = self.resolve_upvalue(0, name) {
+            (
+                OpCode::OP_GET_UPVALUE,
+                OpCode::OP_SET_UPVALUE,
+                index as usize,
+            )
+        } else {
+            let arg = self.name_constant(name);
+            (OpCode::OP_GET_GLOBAL, OpCode::OP_SET_GLOBAL, arg)
+        };
+
+        if can_assign && self.match_token(TokenType::TokenEqual) {
+            self.expression();
+            let span = self.span_from(start);
+            self.emit_variable_at(set_op, arg, span);
+        } else {
+            self.emit_variable_at(get_op, arg, start);
+        }
+    }
+
+    // Local slots and upvalue indexes always fit in one byte, only the
+    // name constant of a global may need the long form.
+    fn emit_variable_at(&mut self, opcode: OpCode, arg: usize, span: Span) {
+        match opcode {
+            OpCode::OP_GET_GLOBAL | OpCode::OP_SET_GLOBAL => {
+                self.emit_constant_instruction_at(opcode, arg, span)
+            }
+            _ => self.emit_bytes_at(opcode as u8, arg as u8, span),
+        }
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.scanner.lexeme(&self.previous);
+        self.named_variable(&name, can_assign);
+    }
+
+    // Named with an underscore because `super` is a keyword in Rust.
+    fn super_(&mut self, _can_assign: bool) {
+        match &self.class_compiler {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class_compiler) if !class_compiler.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            Some(_) => (),
+        }
+
+        let start = self.previous.span;
+        self.consume(TokenType::TokenDot, "Expect '.' after 'super'.");
+        self.consume(TokenType::TokenIdentifier, "Expect superclass method name.");
+        let name = self.previous.clone();
+        let name = self.identifier_constant(&name);
+
+        // The receiver is needed to bind the method, and the superclass to
+        // look it up.
+        self.named_variable("this", false);
+        if self.match_token(TokenType::TokenLeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable("super", false);
+            let span = self.span_from(start);
+            self.emit_constant_instruction_at(OpCode::OP_SUPER_INVOKE, name, span);
+            self.emit_byte_at(arg_count, span);
+        } else {
+            self.named_variable("super", false);
+            let span = self.span_from(start);
+            self.emit_constant_instruction_at(OpCode::OP_GET_SUPER, name, span);
+        }
+    }
+
+    fn this(&mut self, _can_assign: bool) {
+        if self.class_compiler.is_none() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
+    }
+
+    fn unary(&mut self, _can_assign: bool) {
+        let start = self.previous.span;
+        let operator_type = self.previous.ttype;
+
+        // Compile the operand.
+        self.parse_precedence(Precedence::PrecUnary);
+
+        // Emit the operator instruction.
+        let span = self.span_from(start);
+        match operator_type {
+            TokenType::TokenBang => self.emit_byte_at(OpCode::OP_NOT as u8, span),
+            TokenType::TokenMinus => self.emit_byte_at(OpCode::OP_NEGATE as u8, span),
+            _ => (), // Unreachable.
+        }
+    }
+
+    // Starts at the current token and parses any expression at the given
+    // precedence level or higher.
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+        let prefix_rule = match get_rule(self.previous.ttype).prefix {
+            Some(rule) => rule,
+            None => {
+                self.error("Expect expression.");
+                return;
+            }
+        };
+
+        // Only a low precedence expression can be the target of an
+        // assignment, otherwise `a * b = c` would assign to b.
+        let can_assign = precedence <= Precedence::PrecAssignment;
+        let start = self.previous.span;
+        prefix_rule(self, can_assign);
+
+        while precedence <= get_rule(self.current.ttype).precedence {
+            self.advance();
+            if let Some(infix_rule) = get_rule(self.previous.ttype).infix {
+                // Read by the infix rule before it parses anything else.
+                self.infix_start = start;
+                infix_rule(self, can_assign);
+            }
+        }
+
+        if can_assign && self.match_token(TokenType::TokenEqual) {
+            self.error("Invalid assignment target.");
+        }
+    }
+
+    // Global variables are looked up by name at runtime, the name is
+    // stored in the constant table and the instruction refers to it by
+    // index.
+    fn identifier_constant(&mut self, name: &Token) -> usize {
+        let lexeme = self.scanner.lexeme(name);
+        self.name_constant(&lexeme)
+    }
+
+    fn name_constant(&mut self, name: &str) -> usize {
+        let string = self.heap.copy_string(name);
+        self.make_constant(Value::Obj(string))
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(TokenType::TokenRightParen) {
+            loop {
+                self.expression();
+                if arg_count == 255 {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.match_token(TokenType::TokenComma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::TokenRightParen, "Expect ')' after arguments.");
+        arg_count as u8
+    }
+
+    fn and(&mut self, _can_assign: bool) {
+        // If the left operand is falsey skip the right one, the left
+        // value is the result.
+        let end_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.parse_precedence(Precedence::PrecAnd);
+
+        self.patch_jump(end_jump);
+    }
+
+    // The compiler `level` functions out from the current one, zero is
+    // the current compiler itself.
+    fn compiler_at(&mut self, level: usize) -> Option<&mut Compiler> {
+        let mut compiler = &mut self.compiler;
+        for _ in 0..level {
+            compiler = compiler.enclosing.as_deref_mut()?;
+        }
+        Some(compiler)
+    }
+
+    // Walks the locals backwards so the innermost variable with the name
+    // shadows any outer one. Returns None if it is not a local of the
+    // compiler at the given level.
+    fn resolve_local(&mut self, level: usize, name: &str) -> Option<u8> {
+        let found = self
+            .compiler_at(level)?
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)
+            .map(|(slot, local)| (slot, local.depth.is_none()));
+
+        match found {
+            Some((slot, uninitialized)) => {
+                if uninitialized {
+                    self.error("Can't read local variable in its own initializer.");
+                }
+                Some(slot as u8)
+            }
+            None => None,
+        }
+    }
+
+    // Returns the index of the upvalue, reusing it if the function already
+    // captures the same variable.
+    fn add_upvalue(&mut self, level: usize, index: u8, is_local: bool) -> u8 {
+        let upvalues = &mut self
+            .compiler_at(level)
+            .expect("No compiler at level.")
+            .upvalues;
+        let existing = upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local);
+        if let Some(existing) = existing {
+            return existing as u8;
+        }
+
+        if upvalues.len() == UINT8_COUNT {
+            self.error("Too many closure variables in function.");
+            return 0;
+        }
+
+        upvalues.push(Upvalue { index, is_local });
+        (upvalues.len() - 1) as u8
+    }
+
+    // Looks for the variable in the enclosing functions. Each function
+    // between the one declaring the local and the one using it gets an
+    // upvalue, so the value is passed down one closure at a time.
+    fn resolve_upvalue(&mut self, level: usize, name: &str) -> Option<u8> {
+        let enclosing = level + 1;
+        self.compiler_at(enclosing)?;
+
+        if let Some(local) = self.resolve_local(enclosing, name) {
+            if let Some(compiler) = self.compiler_at(enclosing) {
+                compiler.locals[local as usize].is_captured = true;
+            }
+            return Some(self.add_upvalue(level, local, true));
+        }
+
+        if let Some(upvalue) = self.resolve_upvalue(enclosing, name) {
+            return Some(self.add_upvalue(level, upvalue, false));
+        }
+
+        None
+    }
+
+    fn add_local(&mut self, name: String) {
+        if self.compiler.locals.len() == UINT8_COUNT {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.compiler.locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn declare_variable(&mut self) {
+        // Global variables are implicitly declared.
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+
+        let name = self.scanner.lexeme(&self.previous);
+        let scope_depth = self.compiler.scope_depth;
+        let already_declared = self
+            .compiler
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == name);
+
+        if already_declared {
+            self.error("Already a variable with this name in this scope.");
+        }
+
+        self.add_local(name);
+    }
+
+    fn parse_variable(&mut self, error_message: &str) -> usize {
+        self.consume(TokenType::TokenIdentifier, error_message);
+
+        self.declare_variable();
+        // Locals are not looked up by name, so there is no need to store
+        // it in the constant table.
+        if self.compiler.scope_depth > 0 {
+            return 0;
+        }
+
+        let name = self.previous.clone();
+        self.identifier_constant(&name)
+    }
+
+    fn mark_initialized(&mut self) {
+        // A global function declaration has no local to mark.
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+
+        let scope_depth = self.compiler.scope_depth;
+        if let Some(local) = self.compiler.locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        // The value of the initializer is already on top of the stack, in
+        // the slot of the new local, so there is nothing to emit.
+        if self.compiler.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_constant_instruction(OpCode::OP_DEFINE_GLOBAL, global);
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::PrecAssignment);
+    }
+
+    fn block(&mut self) {
+        while !self.check(TokenType::TokenRightBrace) && !self.check(TokenType::TokenEOF) {
+            self.declaration();
+        }
+
+        self.consume(TokenType::TokenRightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler.scope_depth -= 1;
+
+        // Discard the locals declared in the scope, both in the compiler
+        // and in the VM stack.
+        while let Some(local) = self.compiler.locals.last() {
+            match local.depth {
+                Some(depth) if depth > self.compiler.scope_depth => {
+                    if local.is_captured {
+                        self.emit_byte(OpCode::OP_CLOSE_UPVALUE as u8);
+                    } else {
+                        self.emit_byte(OpCode::OP_POP as u8);
+                    }
+                    self.compiler.locals.pop();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        self.init_compiler(function_type);
+        // There is no end_scope(), the whole compiler goes away at the end
+        // of the body and the VM discards the frame.
+        self.begin_scope();
+
+        self.consume(TokenType::TokenLeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::TokenRightParen) {
+            loop {
+                self.compiler.function.arity += 1;
+                if self.compiler.function.arity > 255 {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.match_token(TokenType::TokenComma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::TokenRightParen, "Expect ')' after parameters.");
+        self.consume(
+            TokenType::TokenLeftBrace,
+            "Expect '{' before function body.",
         );
+        self.block();
+
+        let (function, upvalues) = self.end_compiler();
+        let function = self.heap.allocate(Obj::Function(function));
+        let constant = self.make_constant(Value::Obj(function));
+        self.emit_constant_instruction(OpCode::OP_CLOSURE, constant);
 
-        if let TokenType::TokenEOF = token.ttype {
-            break;
+        // Each captured variable is encoded after the function constant.
+        for upvalue in upvalues {
+            self.emit_byte(if upvalue.is_local { 1 } else { 0 });
+            self.emit_byte(upvalue.index);
         }
     }
+
+    fn class_declaration(&mut self) {
+        self.consume(TokenType::TokenIdentifier, "Expect class name.");
+        let class_name = self.previous.clone();
+        let name_constant = self.identifier_constant(&class_name);
+        self.declare_variable();
+
+        self.emit_constant_instruction(OpCode::OP_CLASS, name_constant);
+        self.define_variable(name_constant);
+
+        let class_compiler = ClassCompiler {
+            enclosing: self.class_compiler.take(),
+            has_superclass: false,
+        };
+        self.class_compiler = Some(Box::new(class_compiler));
+
+        let class_name = self.scanner.lexeme(&class_name);
+        if self.match_token(TokenType::TokenLess) {
+            self.consume(TokenType::TokenIdentifier, "Expect superclass name.");
+            let superclass = self.previous.span;
+            self.variable(false);
+
+            if self.scanner.lexeme(&self.previous) == class_name {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // The superclass is stored in a local named `super`, in a scope
+            // of its own so each class gets a different one.
+            self.begin_scope();
+            self.add_local(String::from("super"));
+            self.define_variable(0);
+
+            self.named_variable(&class_name, false);
+            self.emit_byte_at(OpCode::OP_INHERIT as u8, superclass);
+            if let Some(class_compiler) = self.class_compiler.as_mut() {
+                class_compiler.has_superclass = true;
+            }
+        }
+
+        // The methods are bound to the class while it is on top of the
+        // stack.
+        self.named_variable(&class_name, false);
+        self.consume(TokenType::TokenLeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::TokenRightBrace) && !self.check(TokenType::TokenEOF) {
+            self.method();
+        }
+        self.consume(TokenType::TokenRightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::OP_POP as u8);
+
+        if self
+            .class_compiler
+            .as_ref()
+            .is_some_and(|class_compiler| class_compiler.has_superclass)
+        {
+            self.end_scope();
+        }
+
+        self.class_compiler = self
+            .class_compiler
+            .take()
+            .and_then(|class_compiler| class_compiler.enclosing);
+    }
+
+    fn method(&mut self) {
+        self.consume(TokenType::TokenIdentifier, "Expect method name.");
+        let name = self.previous.clone();
+        let constant = self.identifier_constant(&name);
+
+        let function_type = if self.scanner.lexeme(&name) == "init" {
+            FunctionType::TypeInitializer
+        } else {
+            FunctionType::TypeMethod
+        };
+        self.function(function_type);
+        self.emit_constant_instruction(OpCode::OP_METHOD, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // A function can refer to itself in its body, so it is
+        // initialized before compiling it.
+        self.mark_initialized();
+        self.function(FunctionType::TypeFunction);
+        self.define_variable(global);
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(TokenType::TokenEqual) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::OP_NIL as u8);
+        }
+        self.consume(
+            TokenType::TokenSemicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::TokenSemicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::OP_POP as u8);
+    }
+
+    fn for_statement(&mut self) {
+        // The loop variable is scoped to the loop.
+        self.begin_scope();
+        self.consume(TokenType::TokenLeftParen, "Expect '(' after 'for'.");
+        if self.match_token(TokenType::TokenSemicolon) {
+            // No initializer.
+        } else if self.match_token(TokenType::TokenVar) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().count();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::TokenSemicolon) {
+            self.expression();
+            self.consume(
+                TokenType::TokenSemicolon,
+                "Expect ';' after loop condition.",
+            );
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(OpCode::OP_JUMP_IF_FALSE));
+            self.emit_byte(OpCode::OP_POP as u8); // Condition.
+        }
+
+        // The increment appears before the body but runs after it, so
+        // jump over it, run the body, and loop back to it.
+        if !self.match_token(TokenType::TokenRightParen) {
+            let body_jump = self.emit_jump(OpCode::OP_JUMP);
+            let increment_start = self.current_chunk().count();
+            self.expression();
+            self.emit_byte(OpCode::OP_POP as u8);
+            self.consume(TokenType::TokenRightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::OP_POP as u8); // Condition.
+        }
+
+        self.end_scope();
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(TokenType::TokenLeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::TokenRightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::OP_JUMP);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::OP_POP as u8);
+
+        if self.match_token(TokenType::TokenElse) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(TokenType::TokenSemicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::OP_PRINT as u8);
+    }
+
+    fn return_statement(&mut self) {
+        if self.compiler.function_type == FunctionType::TypeScript {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.match_token(TokenType::TokenSemicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler.function_type == FunctionType::TypeInitializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(TokenType::TokenSemicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::OP_RETURN as u8);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().count();
+        self.consume(TokenType::TokenLeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::TokenRightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::OP_JUMP_IF_FALSE);
+        self.emit_byte(OpCode::OP_POP as u8);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::OP_POP as u8);
+    }
+
+    // Skips tokens until a statement boundary, so a single syntax error
+    // doesn't produce a cascade of errors after it.
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+
+        while self.current.ttype != TokenType::TokenEOF {
+            if self.previous.ttype == TokenType::TokenSemicolon {
+                return;
+            }
+            match self.current.ttype {
+                TokenType::TokenClass
+                | TokenType::TokenFun
+                | TokenType::TokenVar
+                | TokenType::TokenFor
+                | TokenType::TokenIf
+                | TokenType::TokenWhile
+                | TokenType::TokenPrint
+                | TokenType::TokenReturn => return,
+                _ => (), // Do nothing.
+            }
+
+            self.advance();
+        }
+    }
+
+    fn declaration(&mut self) {
+        if self.match_token(TokenType::TokenClass) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::TokenFun) {
+            self.fun_declaration();
+        } else if self.match_token(TokenType::TokenVar) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn statement(&mut self) {
+        if self.match_token(TokenType::TokenPrint) {
+            self.print_statement();
+        } else if self.match_token(TokenType::TokenFor) {
+            self.for_statement();
+        } else if self.match_token(TokenType::TokenIf) {
+            self.if_statement();
+        } else if self.match_token(TokenType::TokenReturn) {
+            self.return_statement();
+        } else if self.match_token(TokenType::TokenWhile) {
+            self.while_statement();
+        } else if self.match_token(TokenType::TokenLeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        let token = self.current.clone();
+        self.error_at(&token, message);
+    }
+
+    fn error(&mut self, message: &str) {
+        let token = self.previous.clone();
+        self.error_at(&token, message);
+    }
+
+    fn error_at(&mut self, token: &Token, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+        self.panic_mode = true;
+
+        eprint!("[line {}] Error", token.line);
+
+        match token.ttype {
+            TokenType::TokenEOF => eprint!(" at end"),
+            TokenType::TokenError => (), // Nothing.
+            _ => eprint!(" at '{}'", self.scanner.lexeme(token)),
+        }
+
+        eprintln!(": {}", message);
+        if token.ttype != TokenType::TokenEOF {
+            print_span(&self.source, token.span);
+        }
+        self.had_error = true;
+    }
+}
+
+// Compiles the source code into a function for the top-level script,
+// returns None if there was any compile error.
+pub fn compile(source: &str, heap: &mut Heap, mark_vm_roots: &dyn Fn(&mut Heap)) -> Option<ObjRef> {
+    let mut parser = Parser::new(source, heap, mark_vm_roots);
+
+    parser.advance();
+
+    while !parser.match_token(TokenType::TokenEOF) {
+        parser.declaration();
+    }
+
+    let (function, _) = parser.end_compiler();
+
+    if parser.had_error {
+        return None;
+    }
+    Some(parser.heap.allocate(Obj::Function(function)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::init_heap;
+    use crate::vm::{init_vm, InterpretResult};
+
+    // 300 number constants, so every name after them needs the long forms.
+    fn late_names_source() -> String {
+        let mut source: String = (0..300).map(|i| format!("print {}.5;\n", i)).collect();
+        source.push_str(
+            "var late = 41;\n\
+             late = late + 1;\n\
+             fun f() { return late; }\n\
+             class A { m() { return this.x; } }\n\
+             class B < A { m() { return super.m(); } n() { return super.m; } }\n\
+             var b = B();\n\
+             b.x = f();\n\
+             if (b.m() != 42 or b.n()() != 42) missing();\n",
+        );
+        source
+    }
+
+    fn long_instruction(opcode: OpCode, constant: usize) -> [u8; 4] {
+        [
+            opcode as u8,
+            (constant >> 16) as u8,
+            (constant >> 8) as u8,
+            constant as u8,
+        ]
+    }
+
+    #[test]
+    fn names_after_256_constants() {
+        let mut heap = init_heap();
+        let source = late_names_source();
+
+        let function = compile(&source, &mut heap, &|_| {}).expect("Compile error.");
+
+        let chunk = &heap.as_function(function).chunk;
+        let late = (0..chunk.constant_count())
+            .find(|&index| match chunk.get_constant_value(index) {
+                value @ Value::Obj(obj) => {
+                    heap.is_string(value) && heap.as_string(obj).chars == "late"
+                }
+                _ => false,
+            })
+            .expect("No constant for the global name.");
+        assert!(late > u8::MAX as usize);
+
+        let code: Vec<u8> = (0..chunk.count()).map(|i| chunk.get_byte(i)).collect();
+        let contains = |bytes: [u8; 4]| code.windows(4).any(|window| window == bytes);
+        for &opcode in &[
+            OpCode::OP_DEFINE_GLOBAL_LONG,
+            OpCode::OP_SET_GLOBAL_LONG,
+            OpCode::OP_GET_GLOBAL_LONG,
+        ] {
+            let bytes = long_instruction(opcode, late);
+            assert!(contains(bytes), "No {:?} for the global.", opcode);
+        }
+    }
+
+    // The script calls an undefined function, a runtime error, unless every
+    // long instruction reads back the value it should.
+    #[test]
+    fn long_instructions_run() {
+        let mut vm = init_vm();
+        let result = vm.interpret(&late_names_source());
+        assert!(matches!(result, InterpretResult::InterpretOk));
+    }
 }
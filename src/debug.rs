@@ -1,17 +1,18 @@
 use crate::chunk::{Chunk, OpCode};
-use crate::value::Value;
+use crate::memory::{Heap, ObjRef};
+use crate::value::{Obj, Value};
 
 #[allow(dead_code)]
-pub fn dissassemble_chunk(chunk: &Chunk, name: &str) {
+pub fn dissassemble_chunk(chunk: &Chunk, name: &str, heap: &Heap) {
     println!("== {} ==", name);
 
     let mut offset = 0;
     while offset < chunk.count() {
-        offset = dissassemble_instruction(chunk, offset);
+        offset = dissassemble_instruction(chunk, offset, heap);
     }
 }
 
-pub fn dissassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
+pub fn dissassemble_instruction(chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
     print!("{:04} ", offset);
     if offset > 0 && chunk.get_line(offset) == chunk.get_line(offset - 1) {
         print!("   | ");
@@ -22,31 +23,172 @@ pub fn dissassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
         format!("{:?}", opcode)
     }
     match chunk.get(offset) {
-        opcode @ OpCode::OP_CONSTANT => {
-            return constant_instruction(opcode_name(opcode), &chunk, offset)
+        opcode @ OpCode::OP_CONSTANT
+        | opcode @ OpCode::OP_CONSTANT_LONG
+        | opcode @ OpCode::OP_GET_GLOBAL
+        | opcode @ OpCode::OP_DEFINE_GLOBAL
+        | opcode @ OpCode::OP_SET_GLOBAL
+        | opcode @ OpCode::OP_GET_PROPERTY
+        | opcode @ OpCode::OP_SET_PROPERTY
+        | opcode @ OpCode::OP_GET_SUPER
+        | opcode @ OpCode::OP_CLASS
+        | opcode @ OpCode::OP_METHOD
+        | opcode @ OpCode::OP_GET_GLOBAL_LONG
+        | opcode @ OpCode::OP_DEFINE_GLOBAL_LONG
+        | opcode @ OpCode::OP_SET_GLOBAL_LONG
+        | opcode @ OpCode::OP_GET_PROPERTY_LONG
+        | opcode @ OpCode::OP_SET_PROPERTY_LONG
+        | opcode @ OpCode::OP_GET_SUPER_LONG
+        | opcode @ OpCode::OP_CLASS_LONG
+        | opcode @ OpCode::OP_METHOD_LONG => {
+            constant_instruction(opcode_name(opcode), chunk, offset, heap)
         }
-        opcode @ OpCode::OP_ADD
+        opcode @ OpCode::OP_GET_LOCAL
+        | opcode @ OpCode::OP_SET_LOCAL
+        | opcode @ OpCode::OP_GET_UPVALUE
+        | opcode @ OpCode::OP_SET_UPVALUE
+        | opcode @ OpCode::OP_CALL => byte_instruction(opcode_name(opcode), chunk, offset),
+        opcode @ OpCode::OP_INVOKE
+        | opcode @ OpCode::OP_SUPER_INVOKE
+        | opcode @ OpCode::OP_INVOKE_LONG
+        | opcode @ OpCode::OP_SUPER_INVOKE_LONG => {
+            invoke_instruction(opcode_name(opcode), chunk, offset, heap)
+        }
+        opcode @ OpCode::OP_JUMP | opcode @ OpCode::OP_JUMP_IF_FALSE => {
+            jump_instruction(opcode_name(opcode), 1, chunk, offset)
+        }
+        opcode @ OpCode::OP_LOOP => jump_instruction(opcode_name(opcode), -1, chunk, offset),
+        opcode @ OpCode::OP_CLOSURE | opcode @ OpCode::OP_CLOSURE_LONG => {
+            closure_instruction(opcode_name(opcode), chunk, offset, heap)
+        }
+        opcode @ OpCode::OP_NIL
+        | opcode @ OpCode::OP_TRUE
+        | opcode @ OpCode::OP_FALSE
+        | opcode @ OpCode::OP_POP
+        | opcode @ OpCode::OP_EQUAL
+        | opcode @ OpCode::OP_GREATER
+        | opcode @ OpCode::OP_LESS
+        | opcode @ OpCode::OP_ADD
         | opcode @ OpCode::OP_SUBSTRACT
         | opcode @ OpCode::OP_MULTIPLY
         | opcode @ OpCode::OP_DIVIDE
+        | opcode @ OpCode::OP_NOT
         | opcode @ OpCode::OP_NEGATE
-        | opcode @ OpCode::OP_RETURN => return simple_instruction(opcode_name(opcode), offset),
+        | opcode @ OpCode::OP_PRINT
+        | opcode @ OpCode::OP_CLOSE_UPVALUE
+        | opcode @ OpCode::OP_INHERIT
+        | opcode @ OpCode::OP_RETURN => simple_instruction(opcode_name(opcode), offset),
     }
 }
 
 fn simple_instruction(name: String, offset: usize) -> usize {
     println!("{} ", name);
-    return offset + 1;
+    offset + 1
+}
+
+// Local variable names are gone at runtime, so only the slot number can
+// be shown.
+fn byte_instruction(name: String, chunk: &Chunk, offset: usize) -> usize {
+    let slot = chunk.get_byte(offset + 1);
+    println!("{:<16} {:4}", name, slot);
+    offset + 2
+}
+
+// Shows where the jump lands, sign tells if it goes forward or backward.
+fn jump_instruction(name: String, sign: i64, chunk: &Chunk, offset: usize) -> usize {
+    let jump = (chunk.get_byte(offset + 1) as u16) << 8 | chunk.get_byte(offset + 2) as u16;
+    let target = offset as i64 + 3 + sign * jump as i64;
+    println!("{:<16} {:4} -> {}", name, offset, target);
+    offset + 3
 }
 
-fn constant_instruction(name: String, chunk: &Chunk, offset: usize) -> usize {
-    let constant_index = chunk.get(offset + 1) as usize;
+// The constant operand and the length of the instruction up to the end
+// of it, the long forms use three bytes.
+fn constant_operand(chunk: &Chunk, offset: usize) -> (usize, usize) {
+    if chunk.get(offset).is_long() {
+        let high = chunk.get_byte(offset + 1) as usize;
+        let middle = chunk.get_byte(offset + 2) as usize;
+        let low = chunk.get_byte(offset + 3) as usize;
+        (high << 16 | middle << 8 | low, 4)
+    } else {
+        (chunk.get_byte(offset + 1) as usize, 2)
+    }
+}
+
+// The function constant is followed by a pair of operands for each
+// variable the closure captures.
+fn closure_instruction(name: String, chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
+    let (constant, length) = constant_operand(chunk, offset);
+    let mut offset = offset + length;
+    print!("{:<16} {:4} ", name, constant);
+    let function = chunk.get_constant_value(constant);
+    print_value(function, heap);
+    println!();
+
+    let upvalue_count = heap.as_function(function.as_obj()).upvalue_count;
+    for _ in 0..upvalue_count {
+        let is_local = chunk.get_byte(offset);
+        let index = chunk.get_byte(offset + 1);
+        println!(
+            "{:04}    |                     {} {}",
+            offset,
+            if is_local == 1 { "local" } else { "upvalue" },
+            index
+        );
+        offset += 2;
+    }
+
+    offset
+}
+
+fn constant_instruction(name: String, chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
+    let (constant_index, length) = constant_operand(chunk, offset);
     print!("{:<16} {:4} '", name, constant_index);
-    print_value(chunk.get_constant_value(constant_index));
-    print!("'\n");
-    return offset + 2;
+    print_value(chunk.get_constant_value(constant_index), heap);
+    println!("'");
+    offset + length
+}
+
+// The method name constant is followed by the number of arguments.
+fn invoke_instruction(name: String, chunk: &Chunk, offset: usize, heap: &Heap) -> usize {
+    let (constant, length) = constant_operand(chunk, offset);
+    let arg_count = chunk.get_byte(offset + length);
+    print!("{:<16} ({} args) {:4} '", name, arg_count, constant);
+    print_value(chunk.get_constant_value(constant), heap);
+    println!("'");
+    offset + length + 1
+}
+
+pub fn print_value(value: Value, heap: &Heap) {
+    match value {
+        Value::Bool(b) => print!("{}", b),
+        Value::Nil => print!("nil"),
+        Value::Number(n) => print!("{}", n),
+        Value::Obj(obj) => print_object(obj, heap),
+    }
 }
 
-pub fn print_value(value: Value) {
-    print!("{:.6}", value);
+fn print_object(obj: ObjRef, heap: &Heap) {
+    match heap.get(obj) {
+        Obj::BoundMethod(bound_method) => {
+            print_function(heap.as_closure(bound_method.method).function, heap)
+        }
+        Obj::Class(class) => print!("{}", heap.as_string(class.name).chars),
+        Obj::Closure(closure) => print_function(closure.function, heap),
+        Obj::Function(_) => print_function(obj, heap),
+        Obj::Instance(instance) => {
+            let class = heap.as_class(instance.class);
+            print!("{} instance", heap.as_string(class.name).chars)
+        }
+        Obj::Native(_) => print!("<native fn>"),
+        Obj::String(string) => print!("{}", string.chars),
+        Obj::Upvalue(_) => print!("upvalue"),
+    }
+}
+
+fn print_function(function: ObjRef, heap: &Heap) {
+    match heap.as_function(function).name {
+        Some(name) => print!("<fn {}>", heap.as_string(name).chars),
+        None => print!("<script>"),
+    }
 }
@@ -1,8 +1,13 @@
 mod chunk;
 mod compiler;
 mod debug;
+mod memory;
 mod scanner;
+mod serialize;
+mod span;
+mod table;
 mod value;
+mod verify;
 mod vm;
 
 use std::io::{self, BufRead, Read, Write};
@@ -15,11 +20,14 @@ fn main() {
         repl(&mut vm);
     } else if args.len() == 2 {
         run_file(
-            &args.get(1).expect(&format!("No argument ar index {}", 1)),
+            args.get(1)
+                .unwrap_or_else(|| panic!("No argument ar index {}", 1)),
             &mut vm,
         );
+    } else if args.len() == 5 && args[1] == "compile" && args[3] == "-o" {
+        compile_file(&args[2], &args[4], &mut vm);
     } else {
-        eprintln!("Usage: rlox [path]\n");
+        eprintln!("Usage: rlox [path]\n       rlox compile [path] -o [output]\n");
         std::process::exit(64);
     }
 }
@@ -32,7 +40,8 @@ fn repl(vm: &mut vm::VM) {
         print!("> ");
         std::io::stdout().flush();
 
-        if !stdin.lock().read_line(&mut buffer).is_ok() {
+        // Zero bytes read means the input reached EOF (e.g. Ctrl-D).
+        if let Ok(0) | Err(_) = stdin.lock().read_line(&mut buffer) {
             println!();
             break;
         }
@@ -40,17 +49,36 @@ fn repl(vm: &mut vm::VM) {
     }
 }
 
+// Files ending in .loxc are already compiled, anything else is source
+// code.
 fn run_file(path: &str, vm: &mut vm::VM) {
-    let source = read_file(path).expect(&format!("Error reading file at {}", path));
-    let result = vm.interpret(&source);
+    let result = if path.ends_with(".loxc") {
+        let bytes =
+            std::fs::read(path).unwrap_or_else(|_| panic!("Error reading file at {}", path));
+        vm.interpret_bytecode(&bytes)
+    } else {
+        let source = read_file(path).unwrap_or_else(|_| panic!("Error reading file at {}", path));
+        vm.interpret(&source)
+    };
 
     match result {
         vm::InterpretResult::InterpretCompileError => std::process::exit(65),
         vm::InterpretResult::InterpretRuntimeError => std::process::exit(70),
-        _ => return,
+        vm::InterpretResult::InterpretOk => (),
     }
 }
 
+// Writes the compiled script to the output path instead of running it.
+fn compile_file(path: &str, output: &str, vm: &mut vm::VM) {
+    let source = read_file(path).unwrap_or_else(|_| panic!("Error reading file at {}", path));
+    let bytes = match vm.compile_bytecode(&source) {
+        Some(bytes) => bytes,
+        None => std::process::exit(65),
+    };
+
+    std::fs::write(output, bytes).unwrap_or_else(|_| panic!("Error writing file at {}", output));
+}
+
 fn read_file(path: &str) -> std::io::Result<String> {
     let mut file = std::fs::File::open(path)?;
     let mut content = String::new();
@@ -0,0 +1,513 @@
+#[cfg(feature = "debug-log-gc")]
+use crate::debug;
+use crate::table::{init_table, Table};
+use crate::value::{
+    Obj, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjString, ObjUpvalue, Value,
+};
+
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+// The C version links every object through a raw `Obj*` pointer. Here the
+// objects live in a vector owned by the heap and values refer to them by
+// index, so they can be shared without unsafe code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjRef(usize);
+
+pub struct Heap {
+    // Freed objects leave an empty slot behind, so the references to the
+    // live ones stay valid. The slots are reused by later allocations.
+    objects: Vec<Option<Obj>>,
+    free_slots: Vec<usize>,
+    // The C version keeps the mark bit in the object header.
+    marks: Vec<bool>,
+    // Marked objects whose references haven't been traced yet.
+    gray_stack: Vec<ObjRef>,
+    // Every string ever allocated is interned, so two strings with the
+    // same characters are always the same object. Only the keys are used,
+    // the values are always nil.
+    strings: Table,
+    bytes_allocated: usize,
+    // The next collection runs once bytes_allocated goes over it.
+    next_gc: usize,
+}
+
+impl Heap {
+    fn new() -> Heap {
+        Heap {
+            objects: vec![],
+            free_slots: vec![],
+            marks: vec![],
+            gray_stack: vec![],
+            strings: init_table(),
+            bytes_allocated: 0,
+            next_gc: 1024 * 1024,
+        }
+    }
+
+    pub fn allocate(&mut self, obj: Obj) -> ObjRef {
+        let size = size_of_obj(&obj);
+        self.bytes_allocated += size;
+
+        #[cfg(feature = "debug-log-gc")]
+        let obj_type = type_name(&obj);
+
+        let index = match self.free_slots.pop() {
+            Some(index) => {
+                self.objects[index] = Some(obj);
+                index
+            }
+            None => {
+                self.objects.push(Some(obj));
+                self.marks.push(false);
+                self.objects.len() - 1
+            }
+        };
+
+        #[cfg(feature = "debug-log-gc")]
+        println!("{:?} allocate {} for {}", ObjRef(index), size, obj_type);
+
+        ObjRef(index)
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        self.objects[obj.0]
+            .as_ref()
+            .unwrap_or_else(|| panic!("Object was freed: {:?}", obj))
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        self.objects[obj.0]
+            .as_mut()
+            .unwrap_or_else(|| panic!("Object was freed: {:?}", obj))
+    }
+
+    pub fn is_string(&self, value: Value) -> bool {
+        match value {
+            Value::Obj(obj) => matches!(self.get(obj), Obj::String(_)),
+            _ => false,
+        }
+    }
+
+    pub fn is_class(&self, value: Value) -> bool {
+        match value {
+            Value::Obj(obj) => matches!(self.get(obj), Obj::Class(_)),
+            _ => false,
+        }
+    }
+
+    pub fn is_closure(&self, value: Value) -> bool {
+        match value {
+            Value::Obj(obj) => matches!(self.get(obj), Obj::Closure(_)),
+            _ => false,
+        }
+    }
+
+    pub fn is_instance(&self, value: Value) -> bool {
+        match value {
+            Value::Obj(obj) => matches!(self.get(obj), Obj::Instance(_)),
+            _ => false,
+        }
+    }
+
+    pub fn as_class(&self, obj: ObjRef) -> &ObjClass {
+        match self.get(obj) {
+            Obj::Class(class) => class,
+            other => panic!("Object is not a class: {:?}", other),
+        }
+    }
+
+    pub fn as_class_mut(&mut self, obj: ObjRef) -> &mut ObjClass {
+        match self.get_mut(obj) {
+            Obj::Class(class) => class,
+            other => panic!("Object is not a class: {:?}", other),
+        }
+    }
+
+    pub fn as_closure(&self, obj: ObjRef) -> &ObjClosure {
+        match self.get(obj) {
+            Obj::Closure(closure) => closure,
+            other => panic!("Object is not a closure: {:?}", other),
+        }
+    }
+
+    pub fn as_function(&self, obj: ObjRef) -> &ObjFunction {
+        match self.get(obj) {
+            Obj::Function(function) => function,
+            other => panic!("Object is not a function: {:?}", other),
+        }
+    }
+
+    pub fn as_instance(&self, obj: ObjRef) -> &ObjInstance {
+        match self.get(obj) {
+            Obj::Instance(instance) => instance,
+            other => panic!("Object is not an instance: {:?}", other),
+        }
+    }
+
+    pub fn as_instance_mut(&mut self, obj: ObjRef) -> &mut ObjInstance {
+        match self.get_mut(obj) {
+            Obj::Instance(instance) => instance,
+            other => panic!("Object is not an instance: {:?}", other),
+        }
+    }
+
+    pub fn as_string(&self, obj: ObjRef) -> &ObjString {
+        match self.get(obj) {
+            Obj::String(string) => string,
+            other => panic!("Object is not a string: {:?}", other),
+        }
+    }
+
+    pub fn as_upvalue(&self, obj: ObjRef) -> &ObjUpvalue {
+        match self.get(obj) {
+            Obj::Upvalue(upvalue) => upvalue,
+            other => panic!("Object is not an upvalue: {:?}", other),
+        }
+    }
+
+    pub fn as_upvalue_mut(&mut self, obj: ObjRef) -> &mut ObjUpvalue {
+        match self.get_mut(obj) {
+            Obj::Upvalue(upvalue) => upvalue,
+            other => panic!("Object is not an upvalue: {:?}", other),
+        }
+    }
+
+    // The C version has two flavours, copyString() for characters the heap
+    // doesn't own yet and takeString() for an already allocated buffer.
+    pub fn copy_string(&mut self, chars: &str) -> ObjRef {
+        let hash = hash_string(chars);
+        if let Some(interned) = self.find_string(chars, hash) {
+            return interned;
+        }
+
+        self.allocate_string(chars.to_owned(), hash)
+    }
+
+    pub fn take_string(&mut self, chars: String) -> ObjRef {
+        let hash = hash_string(&chars);
+        if let Some(interned) = self.find_string(&chars, hash) {
+            return interned;
+        }
+
+        self.allocate_string(chars, hash)
+    }
+
+    // The fields of an instance and the methods of a class are added after
+    // the object is allocated. The C version counts the table growing in
+    // reallocate(), here these do it, so the heap size stays right.
+    pub fn set_field(&mut self, instance: ObjRef, name: ObjRef, hash: u32, value: Value) {
+        let fields = &mut self.as_instance_mut(instance).fields;
+        let before = fields.allocated_size();
+        fields.set(name, hash, value);
+        let grown = fields.allocated_size() - before;
+        self.bytes_allocated += grown;
+    }
+
+    pub fn set_method(&mut self, class: ObjRef, name: ObjRef, hash: u32, method: Value) {
+        let methods = &mut self.as_class_mut(class).methods;
+        let before = methods.allocated_size();
+        methods.set(name, hash, method);
+        let grown = methods.allocated_size() - before;
+        self.bytes_allocated += grown;
+    }
+
+    // OP_INHERIT copies the methods of the superclass into the subclass.
+    pub fn inherit(&mut self, subclass: ObjRef, superclass: ObjRef) {
+        let mut methods = std::mem::replace(&mut self.as_class_mut(subclass).methods, init_table());
+        let before = methods.allocated_size();
+        self.as_class(superclass).methods.add_all(&mut methods);
+        let grown = methods.allocated_size() - before;
+        self.as_class_mut(subclass).methods = methods;
+        self.bytes_allocated += grown;
+    }
+
+    fn allocate_string(&mut self, chars: String, hash: u32) -> ObjRef {
+        let string = self.allocate(Obj::String(ObjString { chars, hash }));
+        self.strings.set(string, hash, Value::Nil);
+        string
+    }
+
+    fn find_string(&self, chars: &str, hash: u32) -> Option<ObjRef> {
+        self.strings.find_string(chars, hash, self)
+    }
+
+    // The C version collects from inside reallocate(), where any object
+    // could be sitting in a local variable of the caller. Here the owner
+    // of the roots checks this at points where every live object is
+    // reachable from them, and then calls collect_garbage().
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "debug-stress-gc") || self.bytes_allocated > self.next_gc
+    }
+
+    // Frees every object not reachable from the roots marked by
+    // mark_roots.
+    pub fn collect_garbage<F: FnOnce(&mut Heap)>(&mut self, mark_roots: F) {
+        #[cfg(feature = "debug-log-gc")]
+        let before = self.bytes_allocated;
+        #[cfg(feature = "debug-log-gc")]
+        println!("-- gc begin");
+
+        mark_roots(self);
+        self.trace_references();
+        // The interning table doesn't keep strings alive, the ones that
+        // are about to be freed are removed from it instead.
+        let marks = &self.marks;
+        self.strings.remove_white(|string| marks[string.0]);
+        self.sweep();
+
+        self.next_gc = self.bytes_allocated * GC_HEAP_GROW_FACTOR;
+
+        #[cfg(feature = "debug-log-gc")]
+        {
+            println!("-- gc end");
+            println!(
+                "   collected {} bytes (from {} to {}) next at {}",
+                before - self.bytes_allocated,
+                before,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        if self.marks[obj.0] {
+            return;
+        }
+
+        #[cfg(feature = "debug-log-gc")]
+        {
+            print!("{:?} mark ", obj);
+            debug::print_value(Value::Obj(obj), self);
+            println!();
+        }
+
+        self.marks[obj.0] = true;
+        self.gray_stack.push(obj);
+    }
+
+    // The compiler marks the functions it is still building, they are not
+    // on the heap yet.
+    pub fn mark_function(&mut self, function: &ObjFunction) {
+        for value in function_references(function) {
+            self.mark_value(value);
+        }
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(obj) = self.gray_stack.pop() {
+            self.blacken_object(obj);
+        }
+    }
+
+    fn blacken_object(&mut self, obj: ObjRef) {
+        #[cfg(feature = "debug-log-gc")]
+        {
+            print!("{:?} blacken ", obj);
+            debug::print_value(Value::Obj(obj), self);
+            println!();
+        }
+
+        let references = match self.get(obj) {
+            Obj::BoundMethod(bound_method) => {
+                vec![bound_method.receiver, Value::Obj(bound_method.method)]
+            }
+            Obj::Class(class) => {
+                let mut references = vec![Value::Obj(class.name)];
+                for (key, value) in class.methods.iter() {
+                    references.push(Value::Obj(key));
+                    references.push(value);
+                }
+                references
+            }
+            Obj::Closure(closure) => {
+                let mut references = vec![Value::Obj(closure.function)];
+                references.extend(closure.upvalues.iter().map(|upvalue| Value::Obj(*upvalue)));
+                references
+            }
+            Obj::Function(function) => function_references(function),
+            Obj::Instance(instance) => {
+                let mut references = vec![Value::Obj(instance.class)];
+                for (key, value) in instance.fields.iter() {
+                    references.push(Value::Obj(key));
+                    references.push(value);
+                }
+                references
+            }
+            Obj::Upvalue(upvalue) => upvalue.closed.into_iter().collect(),
+            Obj::Native(_) | Obj::String(_) => vec![],
+        };
+
+        for value in references {
+            self.mark_value(value);
+        }
+    }
+
+    fn sweep(&mut self) {
+        for index in 0..self.objects.len() {
+            if self.marks[index] {
+                self.marks[index] = false;
+                continue;
+            }
+
+            if let Some(obj) = self.objects[index].take() {
+                #[cfg(feature = "debug-log-gc")]
+                println!("{:?} free type {}", ObjRef(index), type_name(&obj));
+
+                self.bytes_allocated -= size_of_obj(&obj);
+                self.free_slots.push(index);
+            }
+        }
+    }
+}
+
+fn function_references(function: &ObjFunction) -> Vec<Value> {
+    let chunk = &function.chunk;
+    let mut references: Vec<Value> = (0..chunk.constant_count())
+        .map(|index| chunk.get_constant_value(index))
+        .collect();
+    references.extend(function.name.map(Value::Obj));
+    references
+}
+
+// An estimate of the memory owned by the object, used to decide when to
+// collect. It is computed the same way when allocating and freeing, the
+// tables of classes and instances only change through the heap, which
+// counts their growth in between.
+fn size_of_obj(obj: &Obj) -> usize {
+    std::mem::size_of::<Obj>()
+        + match obj {
+            Obj::Closure(closure) => closure.upvalues.len() * std::mem::size_of::<ObjRef>(),
+            Obj::Function(function) => {
+                let chunk = &function.chunk;
+                chunk.count()
+                    + chunk.line_run_count() * 2 * std::mem::size_of::<usize>()
+                    + chunk.span_run_count() * 3 * std::mem::size_of::<u32>()
+                    + chunk.constant_count() * std::mem::size_of::<Value>()
+            }
+            Obj::String(string) => string.chars.len(),
+            Obj::Class(class) => class.methods.allocated_size(),
+            Obj::Instance(instance) => instance.fields.allocated_size(),
+            Obj::BoundMethod(_) | Obj::Native(_) | Obj::Upvalue(_) => 0,
+        }
+}
+
+#[cfg(feature = "debug-log-gc")]
+fn type_name(obj: &Obj) -> &'static str {
+    match obj {
+        Obj::BoundMethod(_) => "bound method",
+        Obj::Class(_) => "class",
+        Obj::Closure(_) => "closure",
+        Obj::Function(_) => "function",
+        Obj::Instance(_) => "instance",
+        Obj::Native(_) => "native",
+        Obj::String(_) => "string",
+        Obj::Upvalue(_) => "upvalue",
+    }
+}
+
+pub fn grow_capacity(capacity: usize) -> usize {
+    if capacity < 8 {
+        8
+    } else {
+        capacity * 2
+    }
+}
+
+fn hash_string(chars: &str) -> u32 {
+    hash_bytes(chars.as_bytes())
+}
+
+// FNV-1a
+pub fn hash_bytes(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for &byte in bytes {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+
+    hash
+}
+
+pub fn init_heap() -> Heap {
+    Heap::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn class(heap: &mut Heap, name: &str) -> ObjRef {
+        let name = heap.copy_string(name);
+        heap.allocate(Obj::Class(ObjClass {
+            name,
+            methods: init_table(),
+        }))
+    }
+
+    #[test]
+    fn fields_count_towards_the_heap_size() {
+        let mut heap = init_heap();
+        let class = class(&mut heap, "A");
+        let instance = heap.allocate(Obj::Instance(ObjInstance {
+            class,
+            fields: init_table(),
+        }));
+        let before = heap.bytes_allocated;
+
+        for i in 0..20 {
+            let name = heap.copy_string(&format!("field{}", i));
+            let hash = heap.as_string(name).hash;
+            heap.set_field(instance, name, hash, Value::Number(i as f64));
+        }
+
+        let fields = heap.as_instance(instance).fields.allocated_size();
+        assert!(fields > 0);
+        assert!(heap.bytes_allocated >= before + fields);
+    }
+
+    #[test]
+    fn methods_count_towards_the_heap_size() {
+        let mut heap = init_heap();
+        let superclass = class(&mut heap, "A");
+        let subclass = class(&mut heap, "B");
+        let name = heap.copy_string("m");
+        let hash = heap.as_string(name).hash;
+        heap.set_method(superclass, name, hash, Value::Nil);
+        let before = heap.bytes_allocated;
+
+        heap.inherit(subclass, superclass);
+
+        let methods = heap.as_class(subclass).methods.allocated_size();
+        assert!(methods > 0);
+        assert_eq!(heap.bytes_allocated, before + methods);
+    }
+
+    // Freeing subtracts the size of the tables as they are at that point,
+    // which is what was added while they grew.
+    #[test]
+    fn freeing_returns_to_zero() {
+        let mut heap = init_heap();
+        let class = class(&mut heap, "A");
+        let instance = heap.allocate(Obj::Instance(ObjInstance {
+            class,
+            fields: init_table(),
+        }));
+        for i in 0..20 {
+            let name = heap.copy_string(&format!("field{}", i));
+            let hash = heap.as_string(name).hash;
+            heap.set_field(instance, name, hash, Value::Nil);
+            heap.set_method(class, name, hash, Value::Nil);
+        }
+
+        heap.collect_garbage(|_| {});
+
+        assert_eq!(heap.bytes_allocated, 0);
+    }
+}
@@ -1,3 +1,5 @@
+use crate::span::Span;
+
 pub struct Scanner<'a> {
     // In the C version the scanner use pointers to source code characters,
     // in this Rust version the scanner has a source code reference and
@@ -5,14 +7,22 @@ pub struct Scanner<'a> {
     pub source: &'a str,
     start: usize,
     current: usize,
+    // The same positions as byte offsets, for the spans.
+    start_byte: usize,
+    current_byte: usize,
     line: i32,
+    // A token spreading over several lines, like a string, is reported at
+    // the line where it starts.
+    start_line: i32,
 }
 
+#[derive(Clone)]
 pub struct Token {
     pub ttype: TokenType,
     pub start: usize,
     pub length: usize,
     pub line: i32,
+    pub span: Span,
     // C version force the meaning of start pointer using it as a pointer to
     // error message, in this Rust version without raw pointers, an optional
     // error message string is used instead.
@@ -25,7 +35,11 @@ impl Token {
             ttype,
             start: scanner.start,
             length: scanner.current - scanner.start,
-            line: scanner.line,
+            line: scanner.start_line,
+            span: Span {
+                start: scanner.start_byte,
+                length: scanner.current_byte - scanner.start_byte,
+            },
             message: Option::None,
         }
     }
@@ -35,13 +49,17 @@ impl Token {
             ttype: TokenType::TokenError,
             start: scanner.start,
             length: scanner.current - scanner.start,
-            line: scanner.line,
+            line: scanner.start_line,
+            span: Span {
+                start: scanner.start_byte,
+                length: scanner.current_byte - scanner.start_byte,
+            },
             message: Option::Some(message.to_owned()),
         }
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum TokenType {
     // Single-character tokens.
     TokenLeftParen,
@@ -100,14 +118,29 @@ impl<'a> Scanner<'a> {
             source,
             start: 0,
             current: 0,
+            start_byte: 0,
+            current_byte: 0,
             line: 1,
+            start_line: 1,
         }
     }
 
+    // Tokens only keep indexes into the source, so the characters they
+    // cover have to be recovered from the scanner.
+    pub fn lexeme(&self, token: &Token) -> String {
+        self.source
+            .chars()
+            .skip(token.start)
+            .take(token.length)
+            .collect()
+    }
+
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_byte = self.current_byte;
+        self.start_line = self.line;
 
         if self.is_at_end() {
             return Token::new(TokenType::TokenEOF, self);
@@ -124,57 +157,49 @@ impl<'a> Scanner<'a> {
         }
 
         match c {
-            '(' => return Token::new(TokenType::TokenLeftParen, self),
-            ')' => return Token::new(TokenType::TokenRightParen, self),
-            '{' => return Token::new(TokenType::TokenLeftBrace, self),
-            '}' => return Token::new(TokenType::TokenRightBrace, self),
-            ';' => return Token::new(TokenType::TokenSemicolon, self),
-            ',' => return Token::new(TokenType::TokenComma, self),
-            '.' => return Token::new(TokenType::TokenDot, self),
-            '-' => return Token::new(TokenType::TokenMinus, self),
-            '+' => return Token::new(TokenType::TokenPlus, self),
-            '/' => return Token::new(TokenType::TokenSlash, self),
-            '*' => return Token::new(TokenType::TokenStar, self),
-            '!' => {
-                return Token::new(
-                    if self.match_current('=') {
-                        TokenType::TokenBangEqual
-                    } else {
-                        TokenType::TokenBang
-                    },
-                    self,
-                );
-            }
-            '=' => {
-                return Token::new(
-                    if self.match_current('=') {
-                        TokenType::TokenEqualEqual
-                    } else {
-                        TokenType::TokenEqual
-                    },
-                    self,
-                );
-            }
-            '<' => {
-                return Token::new(
-                    if self.match_current('=') {
-                        TokenType::TokenLessEqual
-                    } else {
-                        TokenType::TokenLess
-                    },
-                    self,
-                );
-            }
-            '>' => {
-                return Token::new(
-                    if self.match_current('=') {
-                        TokenType::TokenGreaterEqual
-                    } else {
-                        TokenType::TokenGreater
-                    },
-                    self,
-                );
-            }
+            '(' => Token::new(TokenType::TokenLeftParen, self),
+            ')' => Token::new(TokenType::TokenRightParen, self),
+            '{' => Token::new(TokenType::TokenLeftBrace, self),
+            '}' => Token::new(TokenType::TokenRightBrace, self),
+            ';' => Token::new(TokenType::TokenSemicolon, self),
+            ',' => Token::new(TokenType::TokenComma, self),
+            '.' => Token::new(TokenType::TokenDot, self),
+            '-' => Token::new(TokenType::TokenMinus, self),
+            '+' => Token::new(TokenType::TokenPlus, self),
+            '/' => Token::new(TokenType::TokenSlash, self),
+            '*' => Token::new(TokenType::TokenStar, self),
+            '!' => Token::new(
+                if self.match_current('=') {
+                    TokenType::TokenBangEqual
+                } else {
+                    TokenType::TokenBang
+                },
+                self,
+            ),
+            '=' => Token::new(
+                if self.match_current('=') {
+                    TokenType::TokenEqualEqual
+                } else {
+                    TokenType::TokenEqual
+                },
+                self,
+            ),
+            '<' => Token::new(
+                if self.match_current('=') {
+                    TokenType::TokenLessEqual
+                } else {
+                    TokenType::TokenLess
+                },
+                self,
+            ),
+            '>' => Token::new(
+                if self.match_current('=') {
+                    TokenType::TokenGreaterEqual
+                } else {
+                    TokenType::TokenGreater
+                },
+                self,
+            ),
             '"' => self.string(),
             _ => Token::new_error("Unexpected character.", self),
         }
@@ -186,10 +211,18 @@ impl<'a> Scanner<'a> {
 
     fn advance(&mut self) -> char {
         self.current += 1;
-        return self.source.chars().nth(self.current - 1).expect(&format!(
-            "No source char at scanner.current - 1 position: {}",
-            self.current - 1,
-        ));
+        let c = self
+            .source
+            .chars()
+            .nth(self.current - 1)
+            .unwrap_or_else(|| {
+                panic!(
+                    "No source char at scanner.current - 1 position: {}",
+                    self.current - 1,
+                )
+            });
+        self.current_byte += c.len_utf8();
+        c
     }
 
     fn match_current(&mut self, expected: char) -> bool {
@@ -197,15 +230,18 @@ impl<'a> Scanner<'a> {
             return false;
         }
 
-        if self.source.chars().nth(self.current).expect(&format!(
-            "No char at scanner.current position: {}",
-            self.current
-        )) != expected
+        if self
+            .source
+            .chars()
+            .nth(self.current)
+            .unwrap_or_else(|| panic!("No char at scanner.current position: {}", self.current))
+            != expected
         {
             return false;
         }
 
         self.current += 1;
+        self.current_byte += expected.len_utf8();
         true
     }
 
@@ -215,22 +251,16 @@ impl<'a> Scanner<'a> {
             match c {
                 ' ' | '\r' | '\t' => {
                     self.advance();
-                    break;
                 }
                 '\n' => {
                     self.line += 1;
                     self.advance();
-                    break;
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                // A comment goes until the end of the line.
+                '/' if self.peek_next() == '/' => {
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
-                    break;
                 }
                 _ => return,
             }
@@ -238,11 +268,7 @@ impl<'a> Scanner<'a> {
     }
 
     fn peek(&mut self) -> char {
-        let peeked = self.source.chars().nth(self.current);
-        match peeked {
-            Some(p) => p,
-            None => '\0',
-        }
+        self.source.chars().nth(self.current).unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
@@ -250,10 +276,7 @@ impl<'a> Scanner<'a> {
             return '\0';
         }
 
-        self.source
-            .chars()
-            .nth(self.current + 1)
-            .expect("No char at scanner.current + 1")
+        self.source.chars().nth(self.current + 1).unwrap_or('\0')
     }
 
     fn string(&mut self) -> Token {
@@ -268,17 +291,18 @@ impl<'a> Scanner<'a> {
             return Token::new_error("Unterminated string.", self);
         }
 
+        // The closing quote.
         self.advance();
 
-        return Token::new(TokenType::TokenString, self);
+        Token::new(TokenType::TokenString, self)
     }
 
     fn is_digit(&self, c: char) -> bool {
-        c >= '0' && c <= '9'
+        c.is_ascii_digit()
     }
 
     fn is_alpha(&self, c: char) -> bool {
-        c >= 'a' && c <= 'z' || c >= 'A' && c <= 'Z' || c == '_'
+        c.is_ascii_alphabetic() || c == '_'
     }
 
     fn identifier(&mut self) -> Token {
@@ -298,17 +322,16 @@ impl<'a> Scanner<'a> {
             .source
             .chars()
             .nth(self.start)
-            .expect(&format!("No char at scanner.start: {}", self.start))
+            .unwrap_or_else(|| panic!("No char at scanner.start: {}", self.start))
         {
             'a' => self.check_keyword(1, 2, "nd", TokenType::TokenAnd),
             'c' => self.check_keyword(1, 4, "lass", TokenType::TokenClass),
             'e' => self.check_keyword(1, 3, "lse", TokenType::TokenElse),
             'f' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).expect(&format!(
-                        "No char ar scanner.start + 1 position: {}",
-                        self.start + 1
-                    )) {
+                    match self.source.chars().nth(self.start + 1).unwrap_or_else(|| {
+                        panic!("No char ar scanner.start + 1 position: {}", self.start + 1)
+                    }) {
                         'a' => self.check_keyword(2, 3, "lse", TokenType::TokenFalse),
                         'o' => self.check_keyword(2, 1, "r", TokenType::TokenFor),
                         'u' => self.check_keyword(2, 1, "n", TokenType::TokenFun),
@@ -326,12 +349,11 @@ impl<'a> Scanner<'a> {
             's' => self.check_keyword(1, 4, "uper", TokenType::TokenSuper),
             't' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).expect(&format!(
-                        "No char at scanner.start + 1 position: {}",
-                        self.start + 1
-                    )) {
+                    match self.source.chars().nth(self.start + 1).unwrap_or_else(|| {
+                        panic!("No char at scanner.start + 1 position: {}", self.start + 1)
+                    }) {
                         'h' => self.check_keyword(2, 2, "is", TokenType::TokenThis),
-                        'r' => self.check_keyword(2, 1, "ue", TokenType::TokenTrue),
+                        'r' => self.check_keyword(2, 2, "ue", TokenType::TokenTrue),
                         _ => TokenType::TokenIdentifier,
                     }
                 } else {
@@ -351,14 +373,20 @@ impl<'a> Scanner<'a> {
         rest: &str,
         ttype: TokenType,
     ) -> TokenType {
+        // The lexeme must be exactly as long as the keyword, and the rest
+        // of its characters must match.
+        if self.current - self.start != start + length {
+            return TokenType::TokenIdentifier;
+        }
+
         let literal: String = self
             .source
             .chars()
-            .skip(self.current + start)
+            .skip(self.start + start)
             .take(length)
             .collect();
 
-        if literal.len() == start + length && literal.eq(&rest) {
+        if literal.eq(&rest) {
             return ttype;
         }
 
@@ -389,6 +417,6 @@ impl<'a> Scanner<'a> {
                 }
             }
         }
-        return Token::new(TokenType::TokenNumber, self);
+        Token::new(TokenType::TokenNumber, self)
     }
 }
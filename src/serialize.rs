@@ -0,0 +1,407 @@
+use crate::chunk::{add_constant, write_chunk, Chunk};
+use crate::memory::{hash_bytes, Heap, ObjRef};
+use crate::value::{Obj, ObjFunction, Value};
+
+// Layout of a .loxc file:
+//
+//   magic     4 bytes, "LOXC"
+//   version   u16
+//   checksum  u32, FNV-1a of the payload
+//   payload   the top-level script function
+//
+// A function is its name, arity, upvalue count and chunk. A chunk is its
+// code, its run-length encoded line table and its constants, each one
+// prefixed with a tag byte. Nested functions are constants of the chunk
+// of the enclosing function, so they are written recursively. Every
+// integer is little-endian and strings are written as UTF-8 with their
+// length first.
+//
+// The source is not included, so a loaded chunk has no spans to point at.
+const MAGIC: &[u8; 4] = b"LOXC";
+const FORMAT_VERSION: u16 = 1;
+const HEADER_SIZE: usize = 10;
+// Nested functions are read recursively, a file nesting them deeper than
+// any real script would is rejected before it runs out of native stack.
+// The script itself counts as the first level.
+pub const FUNCTION_NESTING_MAX: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_FUNCTION: u8 = 4;
+
+pub fn serialize(function: ObjRef, heap: &Heap) -> Vec<u8> {
+    let mut payload = vec![];
+    write_function(&mut payload, heap.as_function(function), heap);
+
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&hash_bytes(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+// Returns the top-level script function, allocated in the heap, or the
+// reason the bytes are not a valid .loxc file.
+pub fn deserialize(bytes: &[u8], heap: &mut Heap) -> Result<ObjRef, String> {
+    if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+        return Err(String::from("Not a .loxc file."));
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+        return Err(format!("Unsupported .loxc version {}.", version));
+    }
+
+    let checksum = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+    let payload = &bytes[HEADER_SIZE..];
+    if hash_bytes(payload) != checksum {
+        return Err(String::from("Checksum mismatch, the file is corrupted."));
+    }
+
+    let mut reader = Reader {
+        bytes: payload,
+        position: 0,
+        depth: 0,
+    };
+    let function = reader.read_function(heap)?;
+    if reader.position != payload.len() {
+        return Err(String::from("Unexpected data after the script."));
+    }
+
+    Ok(heap.allocate(Obj::Function(function)))
+}
+
+fn write_function(out: &mut Vec<u8>, function: &ObjFunction, heap: &Heap) {
+    match function.name {
+        Some(name) => {
+            out.push(1);
+            write_string(out, &heap.as_string(name).chars);
+        }
+        None => out.push(0),
+    }
+    write_u32(out, function.arity);
+    write_u32(out, function.upvalue_count);
+    write_chunk_data(out, &function.chunk, heap);
+}
+
+fn write_chunk_data(out: &mut Vec<u8>, chunk: &Chunk, heap: &Heap) {
+    write_u32(out, chunk.count());
+    out.extend((0..chunk.count()).map(|offset| chunk.get_byte(offset)));
+
+    let lines: Vec<(usize, usize)> = chunk.line_runs().collect();
+    write_u32(out, lines.len());
+    for (offset, line) in lines {
+        write_u32(out, offset);
+        write_u32(out, line);
+    }
+
+    write_u32(out, chunk.constant_count());
+    for index in 0..chunk.constant_count() {
+        match chunk.get_constant_value(index) {
+            Value::Nil => out.push(TAG_NIL),
+            Value::Bool(b) => {
+                out.push(TAG_BOOL);
+                out.push(b as u8);
+            }
+            Value::Number(n) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Obj(obj) => match heap.get(obj) {
+                Obj::String(string) => {
+                    out.push(TAG_STRING);
+                    write_string(out, &string.chars);
+                }
+                Obj::Function(function) => {
+                    out.push(TAG_FUNCTION);
+                    write_function(out, function, heap);
+                }
+                // The compiler only puts strings and functions in the
+                // constant table.
+                other => panic!("Object can't be serialized: {:?}", other),
+            },
+        }
+    }
+}
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn write_string(out: &mut Vec<u8>, chars: &str) {
+    write_u32(out, chars.len());
+    out.extend_from_slice(chars.as_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    // Functions being read, the current one and the ones enclosing it.
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_function(&mut self, heap: &mut Heap) -> Result<ObjFunction, String> {
+        if self.depth == FUNCTION_NESTING_MAX {
+            return Err(String::from("Function nesting too deep."));
+        }
+        self.depth += 1;
+
+        let name = match self.read_u8()? {
+            0 => None,
+            _ => {
+                let chars = self.read_string()?;
+                Some(heap.take_string(chars))
+            }
+        };
+
+        let mut function = ObjFunction::new(name);
+        function.arity = self.read_u32()?;
+        function.upvalue_count = self.read_u32()?;
+        self.read_chunk(&mut function.chunk, heap)?;
+        self.depth -= 1;
+        Ok(function)
+    }
+
+    fn read_chunk(&mut self, chunk: &mut Chunk, heap: &mut Heap) -> Result<(), String> {
+        let code_length = self.read_u32()?;
+        let code = self.read_bytes(code_length)?;
+
+        let run_count = self.read_u32()?;
+        let mut lines = Vec::with_capacity(run_count.min(code_length));
+        for _ in 0..run_count {
+            lines.push((self.read_u32()?, self.read_u32()?));
+        }
+        if code_length > 0 && lines.first().map(|&(offset, _)| offset) != Some(0) {
+            return Err(String::from("Line table doesn't start at the first byte."));
+        }
+
+        // The line table is rebuilt by writing the code again.
+        let mut run = 0;
+        for (offset, &byte) in code.iter().enumerate() {
+            while run + 1 < lines.len() && lines[run + 1].0 <= offset {
+                run += 1;
+            }
+            write_chunk(chunk, byte, lines[run].1, None);
+        }
+
+        let constant_count = self.read_u32()?;
+        for _ in 0..constant_count {
+            let value = match self.read_u8()? {
+                TAG_NIL => Value::Nil,
+                TAG_BOOL => Value::Bool(self.read_u8()? != 0),
+                TAG_NUMBER => {
+                    let bytes = self.read_bytes(8)?;
+                    let mut number = [0; 8];
+                    number.copy_from_slice(bytes);
+                    Value::Number(f64::from_le_bytes(number))
+                }
+                TAG_STRING => {
+                    let chars = self.read_string()?;
+                    Value::Obj(heap.take_string(chars))
+                }
+                TAG_FUNCTION => {
+                    let function = self.read_function(heap)?;
+                    Value::Obj(heap.allocate(Obj::Function(function)))
+                }
+                tag => return Err(format!("Unknown constant tag {}.", tag)),
+            };
+            add_constant(chunk, value);
+        }
+
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.position < length {
+            return Err(String::from("Unexpected end of file."));
+        }
+
+        let bytes = &self.bytes[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<usize, String> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn read_string(&mut self) -> Result<String, String> {
+        let length = self.read_u32()?;
+        let bytes = self.read_bytes(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| String::from("String is not valid UTF-8."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::init_heap;
+    use crate::vm::{init_vm, InterpretResult};
+
+    const SOURCE: &str = "var greeting = \"hello\";
+                          fun add(a, b) { return a + b + 0.5; }
+                          class A { m() { fun inner() { return greeting; } return inner; } }
+                          print add(1, 2);";
+
+    fn compiled(heap: &mut Heap) -> ObjRef {
+        compile(SOURCE, heap, &|_| {}).expect("Compile error.")
+    }
+
+    // The checksum is recomputed, so only the part of the file under test
+    // is wrong.
+    fn with_payload(bytes: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut file = bytes[..HEADER_SIZE].to_vec();
+        file[6..10].copy_from_slice(&hash_bytes(payload).to_le_bytes());
+        file.extend_from_slice(payload);
+        file
+    }
+
+    fn assert_same_function(heap: &Heap, a: &ObjFunction, b: &ObjFunction) {
+        let name = |function: &ObjFunction| function.name.map(|n| heap.as_string(n).chars.clone());
+        assert_eq!(name(a), name(b));
+        assert_eq!(a.arity, b.arity);
+        assert_eq!(a.upvalue_count, b.upvalue_count);
+
+        let (a, b) = (&a.chunk, &b.chunk);
+        assert_eq!(a.count(), b.count());
+        for offset in 0..a.count() {
+            assert_eq!(a.get_byte(offset), b.get_byte(offset));
+        }
+        assert!(a.line_runs().eq(b.line_runs()));
+
+        assert_eq!(a.constant_count(), b.constant_count());
+        for index in 0..a.constant_count() {
+            match (a.get_constant_value(index), b.get_constant_value(index)) {
+                (Value::Number(x), Value::Number(y)) => assert_eq!(x, y),
+                (Value::Obj(x), Value::Obj(y)) => match (heap.get(x), heap.get(y)) {
+                    (Obj::String(x), Obj::String(y)) => assert_eq!(x.chars, y.chars),
+                    (Obj::Function(x), Obj::Function(y)) => assert_same_function(heap, x, y),
+                    (x, y) => panic!("Different constants: {:?} and {:?}", x, y),
+                },
+                (x, y) => panic!("Different constants: {:?} and {:?}", x, y),
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut heap = init_heap();
+        let function = compiled(&mut heap);
+
+        let bytes = serialize(function, &heap);
+        let loaded = deserialize(&bytes, &mut heap).expect("Can't load bytecode.");
+
+        assert_same_function(&heap, heap.as_function(function), heap.as_function(loaded));
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut heap = init_heap();
+        let mut bytes = serialize(compiled(&mut heap), &heap);
+        bytes[0] = b'X';
+
+        let error = deserialize(&bytes, &mut heap).unwrap_err();
+        assert_eq!(error, "Not a .loxc file.");
+    }
+
+    #[test]
+    fn wrong_version() {
+        let mut heap = init_heap();
+        let mut bytes = serialize(compiled(&mut heap), &heap);
+        bytes[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+        let error = deserialize(&bytes, &mut heap).unwrap_err();
+        assert_eq!(
+            error,
+            format!("Unsupported .loxc version {}.", FORMAT_VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut heap = init_heap();
+        let mut bytes = serialize(compiled(&mut heap), &heap);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+
+        let error = deserialize(&bytes, &mut heap).unwrap_err();
+        assert_eq!(error, "Checksum mismatch, the file is corrupted.");
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let mut heap = init_heap();
+        let bytes = serialize(compiled(&mut heap), &heap);
+        let payload = &bytes[HEADER_SIZE..bytes.len() - 3];
+
+        let error = deserialize(&with_payload(&bytes, payload), &mut heap).unwrap_err();
+        assert_eq!(error, "Unexpected end of file.");
+    }
+
+    // Each function but the innermost one has a single constant, the next
+    // function, and no code.
+    fn nested_functions(bytes: &[u8], depth: usize) -> Vec<u8> {
+        let mut payload = vec![];
+        for level in 0..depth {
+            payload.push(0);
+            for &field in &[0, 0, 0, 0] {
+                write_u32(&mut payload, field);
+            }
+            if level + 1 < depth {
+                write_u32(&mut payload, 1);
+                payload.push(TAG_FUNCTION);
+            } else {
+                write_u32(&mut payload, 0);
+            }
+        }
+        with_payload(bytes, &payload)
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit() {
+        let mut heap = init_heap();
+        let bytes = serialize(compiled(&mut heap), &heap);
+
+        let file = nested_functions(&bytes, FUNCTION_NESTING_MAX);
+        assert!(deserialize(&file, &mut heap).is_ok());
+
+        let file = nested_functions(&bytes, FUNCTION_NESTING_MAX + 1);
+        let error = deserialize(&file, &mut heap).unwrap_err();
+        assert_eq!(error, "Function nesting too deep.");
+    }
+
+    // Deep enough to overflow the native stack if it were read.
+    #[test]
+    fn deeply_nested_functions() {
+        let mut heap = init_heap();
+        let bytes = serialize(compiled(&mut heap), &heap);
+        let file = nested_functions(&bytes, 20_000);
+
+        let error = deserialize(&file, &mut heap).unwrap_err();
+        assert_eq!(error, "Function nesting too deep.");
+
+        let result = init_vm().interpret_bytecode(&file);
+        assert!(matches!(result, InterpretResult::InterpretCompileError));
+    }
+
+    #[test]
+    fn trailing_data() {
+        let mut heap = init_heap();
+        let bytes = serialize(compiled(&mut heap), &heap);
+        let mut payload = bytes[HEADER_SIZE..].to_vec();
+        payload.push(0);
+
+        let error = deserialize(&with_payload(&bytes, &payload), &mut heap).unwrap_err();
+        assert_eq!(error, "Unexpected data after the script.");
+    }
+}
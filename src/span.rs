@@ -0,0 +1,116 @@
+// A piece of the source code, as a byte offset and a length in bytes.
+// Tokens index characters, the scanner works out the bytes they cover.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub length: usize,
+}
+
+impl Span {
+    // From the start of this span to the end of the other one.
+    pub fn to(self, other: Span) -> Span {
+        let end = (other.start + other.length).max(self.start + self.length);
+        Span {
+            start: self.start,
+            length: end - self.start,
+        }
+    }
+}
+
+// Shows the source line where the span starts with carets under the span,
+// the way rustc points at the offending code. A span covering several
+// lines is only underlined up to the end of the first one.
+pub fn print_span(source: &str, span: Span) {
+    eprint!("{}", snippet(source, span));
+}
+
+fn snippet(source: &str, span: Span) -> String {
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |newline| newline + 1);
+    let line_end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |newline| start + newline);
+    let line = source[..start].matches('\n').count() + 1;
+
+    // The offsets are in bytes, the carets go under characters.
+    let end = (start + span.length).min(line_end);
+    let width = source[start..end].chars().count().max(1);
+    // Tabs are kept, so the carets line up with the code above them.
+    let padding: String = source[line_start..start]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    let gutter = " ".repeat(line.to_string().len());
+    format!(
+        "{} |\n{} | {}\n{} | {}{}\n",
+        gutter,
+        line,
+        &source[line_start..line_end],
+        gutter,
+        padding,
+        "^".repeat(width)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn underlines_the_span() {
+        let source = "print 1;\nprint a + b;\n";
+        let span = Span {
+            start: 15,
+            length: 5,
+        };
+
+        assert_eq!(
+            snippet(source, span),
+            "  |\n2 | print a + b;\n  |       ^^^^^\n"
+        );
+    }
+
+    // "é" is two bytes but a single caret.
+    #[test]
+    fn byte_offsets_after_multibyte_characters() {
+        let source = "var s = \"é\" + -\"é\";";
+        let span = Span {
+            start: source.find('-').unwrap(),
+            length: 5,
+        };
+
+        assert_eq!(
+            snippet(source, span),
+            "  |\n1 | var s = \"é\" + -\"é\";\n  |               ^^^^\n"
+        );
+    }
+
+    #[test]
+    fn stops_at_the_end_of_the_first_line() {
+        let source = "print \"abc\n  def";
+        let span = Span {
+            start: 6,
+            length: 10,
+        };
+
+        assert_eq!(
+            snippet(source, span),
+            "  |\n1 | print \"abc\n  |       ^^^^\n"
+        );
+    }
+
+    #[test]
+    fn tabs_are_kept_in_the_padding() {
+        let source = "\tprint -x;";
+        let span = Span {
+            start: 7,
+            length: 2,
+        };
+
+        assert_eq!(
+            snippet(source, span),
+            "  |\n1 | \tprint -x;\n  | \t      ^^\n"
+        );
+    }
+}
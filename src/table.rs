@@ -0,0 +1,374 @@
+use crate::memory::{grow_capacity, Heap, ObjRef};
+use crate::value::Value;
+
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+// Keys are always interned strings, so comparing the references is
+// enough to know if two keys are equal. The C version reads the hash from
+// the key string itself, here the entry keeps a copy of it so the table
+// doesn't need to reach into the heap when it grows.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    key: Option<ObjRef>,
+    hash: u32,
+    value: Value,
+}
+
+impl Entry {
+    fn empty() -> Entry {
+        Entry {
+            key: None,
+            hash: 0,
+            value: Value::Nil,
+        }
+    }
+
+    // A deleted entry has no key but a true value, so probe sequences
+    // keep going through it instead of stopping as they do on an empty
+    // entry.
+    fn is_tombstone(&self) -> bool {
+        self.key.is_none() && !self.value.is_nil()
+    }
+}
+
+#[derive(Debug)]
+pub struct Table {
+    // Number of entries in use, tombstones included.
+    count: usize,
+    entries: Vec<Entry>,
+}
+
+impl Table {
+    pub fn get(&self, key: ObjRef, hash: u32) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let entry = &self.entries[find_entry(&self.entries, key, hash)];
+        entry.key.map(|_| entry.value)
+    }
+
+    // Returns true if the key wasn't already in the table.
+    pub fn set(&mut self, key: ObjRef, hash: u32, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.entries.len() as f64 * TABLE_MAX_LOAD {
+            let capacity = grow_capacity(self.entries.len());
+            self.adjust_capacity(capacity);
+        }
+
+        let index = find_entry(&self.entries, key, hash);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_none();
+        // Reusing a tombstone doesn't change the count, it was already
+        // counted when the entry was first used.
+        if is_new_key && entry.value.is_nil() {
+            self.count += 1;
+        }
+
+        *entry = Entry {
+            key: Some(key),
+            hash,
+            value,
+        };
+        is_new_key
+    }
+
+    pub fn delete(&mut self, key: ObjRef, hash: u32) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+
+        // Find the entry.
+        let index = find_entry(&self.entries, key, hash);
+        let entry = &mut self.entries[index];
+        if entry.key.is_none() {
+            return false;
+        }
+
+        // Place a tombstone in the entry.
+        *entry = Entry {
+            key: None,
+            hash: 0,
+            value: Value::Bool(true),
+        };
+        true
+    }
+
+    pub fn add_all(&self, to: &mut Table) {
+        for entry in &self.entries {
+            if let Some(key) = entry.key {
+                to.set(key, entry.hash, entry.value);
+            }
+        }
+    }
+
+    // Memory held by the entries, tombstones and empty ones included.
+    pub fn allocated_size(&self) -> usize {
+        self.entries.len() * std::mem::size_of::<Entry>()
+    }
+
+    // Every key and value in the table.
+    pub fn iter(&self) -> impl Iterator<Item = (ObjRef, Value)> + '_ {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.key.map(|key| (key, entry.value)))
+    }
+
+    pub fn mark(&self, heap: &mut Heap) {
+        for (key, value) in self.iter() {
+            heap.mark_object(key);
+            heap.mark_value(value);
+        }
+    }
+
+    // Deletes the entries whose keys were not marked, so the table doesn't
+    // keep references to objects that are about to be freed.
+    pub fn remove_white<F: Fn(ObjRef) -> bool>(&mut self, is_marked: F) {
+        for entry in self.entries.iter_mut() {
+            if let Some(key) = entry.key {
+                if !is_marked(key) {
+                    *entry = Entry {
+                        key: None,
+                        hash: 0,
+                        value: Value::Bool(true),
+                    };
+                }
+            }
+        }
+    }
+
+    // Used to intern strings, the only lookup that compares the actual
+    // characters instead of the references.
+    pub fn find_string(&self, chars: &str, hash: u32, heap: &Heap) -> Option<ObjRef> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let capacity = self.entries.len();
+        let mut index = hash as usize % capacity;
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                // Stop if we find an empty non-tombstone entry.
+                None if !entry.is_tombstone() => return None,
+                // We found it.
+                Some(key) if entry.hash == hash && heap.as_string(key).chars == chars => {
+                    return Some(key);
+                }
+                _ => (),
+            }
+
+            index = (index + 1) % capacity;
+        }
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let old = std::mem::replace(&mut self.entries, vec![Entry::empty(); capacity]);
+
+        // Tombstones are not copied, so the count is rebuilt.
+        self.count = 0;
+        for entry in old {
+            if let Some(key) = entry.key {
+                let index = find_entry(&self.entries, key, entry.hash);
+                self.entries[index] = entry;
+                self.count += 1;
+            }
+        }
+    }
+}
+
+// Returns the index of the entry for the key, or of the slot where it
+// should be inserted: the first tombstone found along the way, or else the
+// empty entry that ended the probe sequence.
+fn find_entry(entries: &[Entry], key: ObjRef, hash: u32) -> usize {
+    let capacity = entries.len();
+    let mut index = hash as usize % capacity;
+    let mut tombstone = None;
+
+    loop {
+        let entry = &entries[index];
+        match entry.key {
+            None if entry.is_tombstone() => {
+                // We found a tombstone.
+                if tombstone.is_none() {
+                    tombstone = Some(index);
+                }
+            }
+            // Empty entry.
+            None => return tombstone.unwrap_or(index),
+            Some(k) if k == key => {
+                // We found the key.
+                return index;
+            }
+            Some(_) => (),
+        }
+
+        index = (index + 1) % capacity;
+    }
+}
+
+pub fn init_table() -> Table {
+    Table {
+        count: 0,
+        entries: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::init_heap;
+
+    fn key(heap: &mut Heap, chars: &str) -> (ObjRef, u32) {
+        let string = heap.copy_string(chars);
+        (string, heap.as_string(string).hash)
+    }
+
+    fn number(value: Option<Value>) -> Option<f64> {
+        value.map(|v| v.as_number())
+    }
+
+    #[test]
+    fn get_on_empty_table() {
+        let mut heap = init_heap();
+        let (a, hash) = key(&mut heap, "a");
+
+        let table = init_table();
+
+        assert!(table.get(a, hash).is_none());
+    }
+
+    #[test]
+    fn set_and_get() {
+        let mut heap = init_heap();
+        let (a, hash_a) = key(&mut heap, "a");
+        let (b, hash_b) = key(&mut heap, "b");
+
+        let mut table = init_table();
+
+        assert!(table.set(a, hash_a, Value::Number(1.0)));
+        assert!(table.set(b, hash_b, Value::Number(2.0)));
+        assert_eq!(number(table.get(a, hash_a)), Some(1.0));
+        assert_eq!(number(table.get(b, hash_b)), Some(2.0));
+    }
+
+    #[test]
+    fn set_existing_key_overwrites_value() {
+        let mut heap = init_heap();
+        let (a, hash) = key(&mut heap, "a");
+
+        let mut table = init_table();
+
+        assert!(table.set(a, hash, Value::Number(1.0)));
+        assert!(!table.set(a, hash, Value::Number(2.0)));
+        assert_eq!(number(table.get(a, hash)), Some(2.0));
+    }
+
+    #[test]
+    fn delete_removes_key() {
+        let mut heap = init_heap();
+        let (a, hash) = key(&mut heap, "a");
+
+        let mut table = init_table();
+        table.set(a, hash, Value::Number(1.0));
+
+        assert!(table.delete(a, hash));
+        assert!(table.get(a, hash).is_none());
+        assert!(!table.delete(a, hash));
+    }
+
+    #[test]
+    fn probing_goes_through_tombstones() {
+        let mut heap = init_heap();
+        let mut table = init_table();
+
+        // Same hash forces all the keys into a single probe sequence.
+        let keys: Vec<ObjRef> = ["a", "b", "c"]
+            .iter()
+            .map(|chars| heap.copy_string(chars))
+            .collect();
+        for (i, k) in keys.iter().enumerate() {
+            table.set(*k, 7, Value::Number(i as f64));
+        }
+
+        table.delete(keys[0], 7);
+
+        assert!(table.get(keys[0], 7).is_none());
+        assert_eq!(number(table.get(keys[2], 7)), Some(2.0));
+
+        // The tombstone is reused and the count doesn't change.
+        let count = table.count;
+        assert!(table.set(keys[0], 7, Value::Number(3.0)));
+        assert_eq!(table.count, count);
+        assert_eq!(number(table.get(keys[0], 7)), Some(3.0));
+    }
+
+    #[test]
+    fn growing_keeps_every_entry() {
+        let mut heap = init_heap();
+        let mut table = init_table();
+
+        let keys: Vec<(ObjRef, u32)> = (0..100)
+            .map(|i| key(&mut heap, &format!("key{}", i)))
+            .collect();
+        for (i, (k, hash)) in keys.iter().enumerate() {
+            table.set(*k, *hash, Value::Number(i as f64));
+        }
+
+        assert!(table.entries.len() as f64 * TABLE_MAX_LOAD >= 100.0);
+        for (i, (k, hash)) in keys.iter().enumerate() {
+            assert_eq!(number(table.get(*k, *hash)), Some(i as f64));
+        }
+    }
+
+    #[test]
+    fn growing_drops_tombstones() {
+        let mut heap = init_heap();
+        let mut table = init_table();
+
+        let keys: Vec<(ObjRef, u32)> = (0..6)
+            .map(|i| key(&mut heap, &format!("key{}", i)))
+            .collect();
+        for (k, hash) in &keys {
+            table.set(*k, *hash, Value::Nil);
+        }
+        for (k, hash) in &keys {
+            table.delete(*k, *hash);
+        }
+        assert_eq!(table.count, 6);
+
+        let (a, hash) = key(&mut heap, "a");
+        table.set(a, hash, Value::Nil);
+
+        assert_eq!(table.count, 1);
+    }
+
+    #[test]
+    fn add_all_copies_entries() {
+        let mut heap = init_heap();
+        let (a, hash_a) = key(&mut heap, "a");
+        let (b, hash_b) = key(&mut heap, "b");
+
+        let mut from = init_table();
+        from.set(a, hash_a, Value::Number(1.0));
+        from.set(b, hash_b, Value::Number(2.0));
+        let mut to = init_table();
+        to.set(a, hash_a, Value::Number(0.0));
+
+        from.add_all(&mut to);
+
+        assert_eq!(number(to.get(a, hash_a)), Some(1.0));
+        assert_eq!(number(to.get(b, hash_b)), Some(2.0));
+    }
+
+    #[test]
+    fn find_string_compares_characters() {
+        let mut heap = init_heap();
+        let (a, hash) = key(&mut heap, "a");
+
+        let mut table = init_table();
+        table.set(a, hash, Value::Nil);
+
+        assert_eq!(table.find_string("a", hash, &heap), Some(a));
+        assert_eq!(table.find_string("b", hash, &heap), None);
+    }
+}
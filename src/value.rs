@@ -1,4 +1,161 @@
-pub type Value = f64;
+use crate::chunk::{init_chunk, Chunk};
+use crate::memory::ObjRef;
+use crate::table::Table;
+
+// The C version stores values in a struct with a type tag and an union,
+// and uses macros to check the tag and unwrap the payload. A Rust enum
+// is already a tagged union, so the macros become methods.
+#[derive(Debug, Clone, Copy)]
+pub enum Value {
+    Bool(bool),
+    Nil,
+    Number(f64),
+    // Anything bigger than a number lives on the heap.
+    Obj(ObjRef),
+}
+
+impl Value {
+    pub fn is_bool(&self) -> bool {
+        matches!(self, Value::Bool(_))
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(self, Value::Number(_))
+    }
+
+    pub fn as_bool(&self) -> bool {
+        match self {
+            Value::Bool(b) => *b,
+            _ => panic!("Value is not a boolean: {:?}", self),
+        }
+    }
+
+    pub fn as_number(&self) -> f64 {
+        match self {
+            Value::Number(n) => *n,
+            _ => panic!("Value is not a number: {:?}", self),
+        }
+    }
+
+    pub fn as_obj(&self) -> ObjRef {
+        match self {
+            Value::Obj(obj) => *obj,
+            _ => panic!("Value is not an object: {:?}", self),
+        }
+    }
+
+    // nil and false are falsey and every other value behaves like true.
+    pub fn is_falsey(&self) -> bool {
+        self.is_nil() || (self.is_bool() && !self.as_bool())
+    }
+}
+
+#[derive(Debug)]
+pub enum Obj {
+    BoundMethod(ObjBoundMethod),
+    Class(ObjClass),
+    Closure(ObjClosure),
+    Function(ObjFunction),
+    Instance(ObjInstance),
+    Native(ObjNative),
+    String(ObjString),
+    Upvalue(ObjUpvalue),
+}
+
+// Functions are first class, each one owns the chunk with its bytecode.
+#[derive(Debug)]
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // None for the top-level script.
+    pub name: Option<ObjRef>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<ObjRef>) -> ObjFunction {
+        ObjFunction {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: init_chunk(),
+            name,
+        }
+    }
+}
+
+// The runtime representation of a function declaration, a function
+// together with the variables it captured. Several closures can share the
+// same function.
+#[derive(Debug)]
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+// A captured variable. While the variable is still on the VM stack the
+// upvalue is open and refers to its slot, when the variable goes out of
+// scope the value is moved into the upvalue and it becomes closed.
+#[derive(Debug)]
+pub struct ObjUpvalue {
+    pub location: usize,
+    pub closed: Option<Value>,
+}
+
+#[derive(Debug)]
+pub struct ObjClass {
+    pub name: ObjRef,
+    pub methods: Table,
+}
+
+// Each instance has its own fields, they can be added at any time by
+// assigning to them.
+#[derive(Debug)]
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: Table,
+}
+
+// A method accessed on an instance, it remembers the instance so `this`
+// is bound to it when the method is called later.
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// Functions implemented in Rust. They get the arguments of the call and
+// return either the result or the message of a runtime error.
+pub type NativeFn = fn(&[Value]) -> Result<Value, String>;
+
+#[derive(Debug)]
+pub struct ObjNative {
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+#[derive(Debug)]
+pub struct ObjString {
+    pub chars: String,
+    // Cached, strings are immutable so the hash never changes.
+    pub hash: u32,
+}
+
+// Values of different types are never equal, there are no implicit
+// conversions in Lox. Strings are interned, so two objects are equal
+// only if they are the same object.
+pub fn values_equal(a: Value, b: Value) -> bool {
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a == b,
+        (Value::Nil, Value::Nil) => true,
+        (Value::Number(a), Value::Number(b)) => a == b,
+        (Value::Obj(a), Value::Obj(b)) => a == b,
+        _ => false,
+    }
+}
 
 #[derive(Debug)]
 pub struct ValueArray {
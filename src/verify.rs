@@ -0,0 +1,706 @@
+use crate::chunk::{Chunk, OpCode};
+use crate::memory::{Heap, ObjRef};
+use crate::serialize::FUNCTION_NESTING_MAX;
+use crate::value::{Obj, ObjFunction, Value};
+use crate::vm::STACK_MAX;
+use std::fmt;
+
+// What can be wrong with the bytecode of a function. Offsets point to the
+// first byte of the faulting instruction.
+#[derive(Debug, PartialEq)]
+pub enum VerifyErrorKind {
+    // The VM calls the script with no arguments and nothing to capture.
+    ScriptArity(usize),
+    ScriptUpvalues(usize),
+    // Loading refuses files nesting functions deeper than this, the
+    // verifier holds functions built any other way to the same limit.
+    NestingTooDeep,
+    UnknownOpcode(u8),
+    // The operands go past the end of the chunk.
+    TruncatedInstruction,
+    ConstantOutOfRange(usize),
+    WrongConstantType {
+        index: usize,
+        expected: &'static str,
+    },
+    LocalOutOfRange(usize),
+    UpvalueOutOfRange(usize),
+    InvalidUpvalueFlag(u8),
+    JumpOutOfBounds(isize),
+    // The target is inside the operands of another instruction.
+    JumpIntoInstruction(usize),
+    // Popping slot zero of the frame counts as an underflow too.
+    StackUnderflow,
+    // The function needs more slots than the whole VM stack.
+    StackOverflow(usize),
+    // Two paths reach the same instruction with different stack depths.
+    StackMismatch {
+        expected: usize,
+        found: usize,
+    },
+    // The last instruction can continue past the end of the chunk.
+    FallsOffEnd,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    // The function name, or "script" for the top-level code.
+    pub function: String,
+    pub offset: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "in {} at offset {}: ", self.function, self.offset)?;
+        match &self.kind {
+            VerifyErrorKind::ScriptArity(arity) => {
+                write!(f, "the script can't have parameters, it has {}", arity)
+            }
+            VerifyErrorKind::ScriptUpvalues(count) => {
+                write!(f, "the script can't capture variables, it has {}", count)
+            }
+            VerifyErrorKind::NestingTooDeep => write!(
+                f,
+                "functions are nested more than {} deep",
+                FUNCTION_NESTING_MAX
+            ),
+            VerifyErrorKind::UnknownOpcode(byte) => write!(f, "unknown opcode {}", byte),
+            VerifyErrorKind::TruncatedInstruction => write!(f, "truncated instruction"),
+            VerifyErrorKind::ConstantOutOfRange(index) => {
+                write!(f, "constant {} doesn't exist", index)
+            }
+            VerifyErrorKind::WrongConstantType { index, expected } => {
+                write!(f, "constant {} is not a {}", index, expected)
+            }
+            VerifyErrorKind::LocalOutOfRange(slot) => {
+                write!(f, "local slot {} is not on the stack", slot)
+            }
+            VerifyErrorKind::UpvalueOutOfRange(index) => {
+                write!(f, "upvalue {} doesn't exist", index)
+            }
+            VerifyErrorKind::InvalidUpvalueFlag(flag) => {
+                write!(f, "invalid upvalue flag {}", flag)
+            }
+            VerifyErrorKind::JumpOutOfBounds(target) => {
+                write!(f, "jump to {} is out of the chunk", target)
+            }
+            VerifyErrorKind::JumpIntoInstruction(target) => {
+                write!(f, "jump to {} lands inside an instruction", target)
+            }
+            VerifyErrorKind::StackUnderflow => write!(f, "stack underflow"),
+            VerifyErrorKind::StackOverflow(depth) => write!(
+                f,
+                "needs {} stack slots, the VM only has {}",
+                depth, STACK_MAX
+            ),
+            VerifyErrorKind::StackMismatch { expected, found } => write!(
+                f,
+                "stack depth is {} on one path and {} on another",
+                expected, found
+            ),
+            VerifyErrorKind::FallsOffEnd => write!(f, "execution falls off the end of the chunk"),
+        }
+    }
+}
+
+// Checks the script function and every function nested in it, so the VM
+// can run them without panicking or reading out of bounds. The compiler
+// always produces valid code, this is for chunks loaded from a file.
+pub fn verify(function: ObjRef, heap: &Heap) -> Result<(), VerifyError> {
+    let script = heap.as_function(function);
+    let verifier = Verifier {
+        function: script,
+        heap,
+    };
+    if script.arity != 0 {
+        return Err(verifier.error(0, VerifyErrorKind::ScriptArity(script.arity)));
+    }
+    if script.upvalue_count != 0 {
+        let count = script.upvalue_count;
+        return Err(verifier.error(0, VerifyErrorKind::ScriptUpvalues(count)));
+    }
+
+    // An explicit stack instead of recursion, each function with how deep
+    // it is nested, the script being the first level.
+    let mut pending = vec![(script, 1)];
+    while let Some((function, depth)) = pending.pop() {
+        let verifier = Verifier { function, heap };
+        if depth > FUNCTION_NESTING_MAX {
+            return Err(verifier.error(0, VerifyErrorKind::NestingTooDeep));
+        }
+        let instructions = verifier.decode()?;
+        verifier.check_stack(&instructions)?;
+
+        // Reversed, so the functions are checked in the order they appear.
+        let chunk = &function.chunk;
+        for index in (0..chunk.constant_count()).rev() {
+            if let Value::Obj(obj) = chunk.get_constant_value(index) {
+                if let Obj::Function(nested) = heap.get(obj) {
+                    pending.push((nested, depth + 1));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Opcode and constant operand of an instruction taking a constant.
+fn constant_length(opcode: OpCode) -> usize {
+    if opcode.is_long() {
+        4
+    } else {
+        2
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Instruction {
+    opcode: OpCode,
+    // Opcode and operands.
+    length: usize,
+}
+
+struct Verifier<'a> {
+    function: &'a ObjFunction,
+    heap: &'a Heap,
+}
+
+impl<'a> Verifier<'a> {
+    fn chunk(&self) -> &Chunk {
+        &self.function.chunk
+    }
+
+    fn error(&self, offset: usize, kind: VerifyErrorKind) -> VerifyError {
+        let function = match self.function.name {
+            Some(name) => self.heap.as_string(name).chars.clone(),
+            None => String::from("script"),
+        };
+        VerifyError {
+            function,
+            offset,
+            kind,
+        }
+    }
+
+    // Walks the code from start to end, finding where each instruction
+    // begins and checking the operands that don't depend on the stack.
+    // The result has an entry for every byte, only instruction starts are
+    // Some.
+    fn decode(&self) -> Result<Vec<Option<Instruction>>, VerifyError> {
+        let chunk = self.chunk();
+        let mut instructions = vec![None; chunk.count()];
+
+        let mut offset = 0;
+        while offset < chunk.count() {
+            let opcode = OpCode::from_byte(chunk.get_byte(offset))
+                .map_err(|byte| self.error(offset, VerifyErrorKind::UnknownOpcode(byte)))?;
+
+            let length = match opcode {
+                OpCode::OP_GET_LOCAL
+                | OpCode::OP_SET_LOCAL
+                | OpCode::OP_GET_UPVALUE
+                | OpCode::OP_SET_UPVALUE
+                | OpCode::OP_CALL => 2,
+                OpCode::OP_JUMP | OpCode::OP_JUMP_IF_FALSE | OpCode::OP_LOOP => 3,
+                OpCode::OP_CONSTANT
+                | OpCode::OP_CONSTANT_LONG
+                | OpCode::OP_GET_GLOBAL
+                | OpCode::OP_GET_GLOBAL_LONG
+                | OpCode::OP_DEFINE_GLOBAL
+                | OpCode::OP_DEFINE_GLOBAL_LONG
+                | OpCode::OP_SET_GLOBAL
+                | OpCode::OP_SET_GLOBAL_LONG
+                | OpCode::OP_GET_PROPERTY
+                | OpCode::OP_GET_PROPERTY_LONG
+                | OpCode::OP_SET_PROPERTY
+                | OpCode::OP_SET_PROPERTY_LONG
+                | OpCode::OP_GET_SUPER
+                | OpCode::OP_GET_SUPER_LONG
+                | OpCode::OP_CLASS
+                | OpCode::OP_CLASS_LONG
+                | OpCode::OP_METHOD
+                | OpCode::OP_METHOD_LONG => constant_length(opcode),
+                // The argument count follows the method name.
+                OpCode::OP_INVOKE
+                | OpCode::OP_INVOKE_LONG
+                | OpCode::OP_SUPER_INVOKE
+                | OpCode::OP_SUPER_INVOKE_LONG => constant_length(opcode) + 1,
+                OpCode::OP_CLOSURE | OpCode::OP_CLOSURE_LONG => {
+                    self.operands(offset, constant_length(opcode))?;
+                    let constant = self.constant_operand(offset, opcode);
+                    let function = self.constant_function(offset, constant)?;
+                    constant_length(opcode) + 2 * function.upvalue_count
+                }
+                _ => 1,
+            };
+            self.operands(offset, length)?;
+            self.check_operands(offset, opcode)?;
+
+            instructions[offset] = Some(Instruction { opcode, length });
+            offset += length;
+        }
+
+        Ok(instructions)
+    }
+
+    fn operands(&self, offset: usize, length: usize) -> Result<(), VerifyError> {
+        if offset + length > self.chunk().count() {
+            return Err(self.error(offset, VerifyErrorKind::TruncatedInstruction));
+        }
+        Ok(())
+    }
+
+    // The constant index of an instruction taking a constant, its operand
+    // is one byte long or three in the long form.
+    fn constant_operand(&self, offset: usize, opcode: OpCode) -> usize {
+        let byte = |n: usize| self.chunk().get_byte(offset + n) as usize;
+        if opcode.is_long() {
+            byte(1) << 16 | byte(2) << 8 | byte(3)
+        } else {
+            byte(1)
+        }
+    }
+
+    fn check_operands(&self, offset: usize, opcode: OpCode) -> Result<(), VerifyError> {
+        let chunk = self.chunk();
+        let byte = |n: usize| chunk.get_byte(offset + n) as usize;
+
+        match opcode {
+            OpCode::OP_CONSTANT | OpCode::OP_CONSTANT_LONG => self
+                .constant(offset, self.constant_operand(offset, opcode))
+                .map(|_| ()),
+            OpCode::OP_GET_GLOBAL
+            | OpCode::OP_GET_GLOBAL_LONG
+            | OpCode::OP_DEFINE_GLOBAL
+            | OpCode::OP_DEFINE_GLOBAL_LONG
+            | OpCode::OP_SET_GLOBAL
+            | OpCode::OP_SET_GLOBAL_LONG
+            | OpCode::OP_GET_PROPERTY
+            | OpCode::OP_GET_PROPERTY_LONG
+            | OpCode::OP_SET_PROPERTY
+            | OpCode::OP_SET_PROPERTY_LONG
+            | OpCode::OP_GET_SUPER
+            | OpCode::OP_GET_SUPER_LONG
+            | OpCode::OP_INVOKE
+            | OpCode::OP_INVOKE_LONG
+            | OpCode::OP_SUPER_INVOKE
+            | OpCode::OP_SUPER_INVOKE_LONG
+            | OpCode::OP_CLASS
+            | OpCode::OP_CLASS_LONG
+            | OpCode::OP_METHOD
+            | OpCode::OP_METHOD_LONG => {
+                self.constant_string(offset, self.constant_operand(offset, opcode))
+            }
+            OpCode::OP_GET_UPVALUE | OpCode::OP_SET_UPVALUE => self.upvalue(offset, byte(1)),
+            OpCode::OP_CLOSURE | OpCode::OP_CLOSURE_LONG => {
+                let constant = self.constant_operand(offset, opcode);
+                let function = self.constant_function(offset, constant)?;
+                let pairs = constant_length(opcode);
+                for i in 0..function.upvalue_count {
+                    let is_local = chunk.get_byte(offset + pairs + 2 * i);
+                    let index = byte(pairs + 1 + 2 * i);
+                    match is_local {
+                        // Local slots are checked against the stack depth.
+                        1 => (),
+                        0 => self.upvalue(offset, index)?,
+                        flag => {
+                            return Err(
+                                self.error(offset, VerifyErrorKind::InvalidUpvalueFlag(flag))
+                            )
+                        }
+                    }
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn constant(&self, offset: usize, index: usize) -> Result<Value, VerifyError> {
+        if index >= self.chunk().constant_count() {
+            return Err(self.error(offset, VerifyErrorKind::ConstantOutOfRange(index)));
+        }
+        Ok(self.chunk().get_constant_value(index))
+    }
+
+    fn constant_string(&self, offset: usize, index: usize) -> Result<(), VerifyError> {
+        match self.constant(offset, index)? {
+            Value::Obj(obj) if matches!(self.heap.get(obj), Obj::String(_)) => Ok(()),
+            _ => Err(self.error(
+                offset,
+                VerifyErrorKind::WrongConstantType {
+                    index,
+                    expected: "string",
+                },
+            )),
+        }
+    }
+
+    fn constant_function(
+        &self,
+        offset: usize,
+        index: usize,
+    ) -> Result<&'a ObjFunction, VerifyError> {
+        match self.constant(offset, index)? {
+            Value::Obj(obj) => match self.heap.get(obj) {
+                Obj::Function(function) => Ok(function),
+                _ => Err(self.error(
+                    offset,
+                    VerifyErrorKind::WrongConstantType {
+                        index,
+                        expected: "function",
+                    },
+                )),
+            },
+            _ => Err(self.error(
+                offset,
+                VerifyErrorKind::WrongConstantType {
+                    index,
+                    expected: "function",
+                },
+            )),
+        }
+    }
+
+    fn upvalue(&self, offset: usize, index: usize) -> Result<(), VerifyError> {
+        if index >= self.function.upvalue_count {
+            return Err(self.error(offset, VerifyErrorKind::UpvalueOutOfRange(index)));
+        }
+        Ok(())
+    }
+
+    // Follows every path through the code keeping track of the stack
+    // depth, measured from the start of the call frame. Slot zero and the
+    // parameters are there when the function starts.
+    fn check_stack(&self, instructions: &[Option<Instruction>]) -> Result<(), VerifyError> {
+        let chunk = self.chunk();
+        let mut depths: Vec<Option<usize>> = vec![None; chunk.count()];
+        let mut pending = vec![];
+
+        if chunk.count() == 0 {
+            return Err(self.error(0, VerifyErrorKind::FallsOffEnd));
+        }
+        depths[0] = Some(self.function.arity + 1);
+        pending.push(0);
+
+        while let Some(offset) = pending.pop() {
+            let depth = depths[offset].expect("Pending instruction without depth.");
+            let instruction = instructions[offset].expect("Pending offset is not an instruction.");
+            let byte = |n: usize| chunk.get_byte(offset + n) as usize;
+
+            let (pops, pushes) = match instruction.opcode {
+                OpCode::OP_CONSTANT
+                | OpCode::OP_CONSTANT_LONG
+                | OpCode::OP_NIL
+                | OpCode::OP_TRUE
+                | OpCode::OP_FALSE
+                | OpCode::OP_GET_GLOBAL
+                | OpCode::OP_GET_GLOBAL_LONG
+                | OpCode::OP_GET_UPVALUE
+                | OpCode::OP_CLASS
+                | OpCode::OP_CLASS_LONG => (0, 1),
+                OpCode::OP_GET_LOCAL => {
+                    self.local(offset, byte(1), depth)?;
+                    (0, 1)
+                }
+                OpCode::OP_SET_LOCAL => {
+                    self.local(offset, byte(1), depth)?;
+                    (1, 1)
+                }
+                OpCode::OP_CLOSURE | OpCode::OP_CLOSURE_LONG => {
+                    let constant = self.constant_operand(offset, instruction.opcode);
+                    let function = self.constant_function(offset, constant)?;
+                    let pairs = constant_length(instruction.opcode);
+                    for i in 0..function.upvalue_count {
+                        if byte(pairs + 2 * i) == 1 {
+                            self.local(offset, byte(pairs + 1 + 2 * i), depth)?;
+                        }
+                    }
+                    (0, 1)
+                }
+                OpCode::OP_POP
+                | OpCode::OP_DEFINE_GLOBAL
+                | OpCode::OP_DEFINE_GLOBAL_LONG
+                | OpCode::OP_PRINT
+                | OpCode::OP_CLOSE_UPVALUE
+                | OpCode::OP_RETURN => (1, 0),
+                OpCode::OP_SET_GLOBAL
+                | OpCode::OP_SET_GLOBAL_LONG
+                | OpCode::OP_SET_UPVALUE
+                | OpCode::OP_GET_PROPERTY
+                | OpCode::OP_GET_PROPERTY_LONG
+                | OpCode::OP_NOT
+                | OpCode::OP_NEGATE
+                | OpCode::OP_JUMP_IF_FALSE => (1, 1),
+                OpCode::OP_SET_PROPERTY
+                | OpCode::OP_SET_PROPERTY_LONG
+                | OpCode::OP_GET_SUPER
+                | OpCode::OP_GET_SUPER_LONG
+                | OpCode::OP_EQUAL
+                | OpCode::OP_GREATER
+                | OpCode::OP_LESS
+                | OpCode::OP_ADD
+                | OpCode::OP_SUBSTRACT
+                | OpCode::OP_MULTIPLY
+                | OpCode::OP_DIVIDE
+                | OpCode::OP_INHERIT
+                | OpCode::OP_METHOD
+                | OpCode::OP_METHOD_LONG => (2, 1),
+                OpCode::OP_JUMP | OpCode::OP_LOOP => (0, 0),
+                // The callee or receiver is below the arguments.
+                OpCode::OP_CALL => (byte(1) + 1, 1),
+                OpCode::OP_INVOKE | OpCode::OP_INVOKE_LONG => (byte(instruction.length - 1) + 1, 1),
+                OpCode::OP_SUPER_INVOKE | OpCode::OP_SUPER_INVOKE_LONG => {
+                    (byte(instruction.length - 1) + 2, 1)
+                }
+            };
+
+            // Slot zero holds the function being called, it is never
+            // popped, the VM discards the whole frame on return.
+            if pops >= depth {
+                return Err(self.error(offset, VerifyErrorKind::StackUnderflow));
+            }
+            let depth = depth - pops + pushes;
+            if depth > STACK_MAX {
+                return Err(self.error(offset, VerifyErrorKind::StackOverflow(depth)));
+            }
+
+            // Signed, a malformed loop can jump back past the start.
+            let next = (offset + instruction.length) as isize;
+            let jump = || (byte(1) << 8 | byte(2)) as isize;
+            let successors = match instruction.opcode {
+                OpCode::OP_RETURN => vec![],
+                OpCode::OP_JUMP => vec![next + jump()],
+                OpCode::OP_JUMP_IF_FALSE => vec![next, next + jump()],
+                OpCode::OP_LOOP => vec![next - jump()],
+                _ => vec![next],
+            };
+
+            for successor in successors {
+                let target = match successor {
+                    target if target >= 0 && (target as usize) < chunk.count() => target as usize,
+                    // Only a jump can land outside the chunk on purpose.
+                    target if target != next => {
+                        return Err(self.error(offset, VerifyErrorKind::JumpOutOfBounds(target)))
+                    }
+                    _ => return Err(self.error(offset, VerifyErrorKind::FallsOffEnd)),
+                };
+
+                if instructions[target].is_none() {
+                    return Err(self.error(offset, VerifyErrorKind::JumpIntoInstruction(target)));
+                }
+
+                match depths[target] {
+                    Some(expected) if expected != depth => {
+                        return Err(self.error(
+                            target,
+                            VerifyErrorKind::StackMismatch {
+                                expected,
+                                found: depth,
+                            },
+                        ));
+                    }
+                    Some(_) => (),
+                    None => {
+                        depths[target] = Some(depth);
+                        pending.push(target);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn local(&self, offset: usize, slot: usize, depth: usize) -> Result<(), VerifyError> {
+        if slot >= depth {
+            return Err(self.error(offset, VerifyErrorKind::LocalOutOfRange(slot)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{add_constant, write_chunk};
+    use crate::compiler::compile;
+    use crate::memory::init_heap;
+    use crate::serialize::serialize;
+    use crate::vm::{init_vm, InterpretResult};
+
+    // The constant table only has "x", for the instructions taking a name.
+    fn function(heap: &mut Heap, code: &[u8]) -> ObjFunction {
+        let mut function = ObjFunction::new(None);
+        for &byte in code {
+            write_chunk(&mut function.chunk, byte, 1, None);
+        }
+        let name = heap.copy_string("x");
+        add_constant(&mut function.chunk, Value::Obj(name));
+        function
+    }
+
+    fn script(heap: &mut Heap, code: &[u8]) -> ObjRef {
+        let function = function(heap, code);
+        heap.allocate(Obj::Function(function))
+    }
+
+    fn error_kind(code: &[u8]) -> Option<VerifyErrorKind> {
+        let mut heap = init_heap();
+        let function = script(&mut heap, code);
+        verify(function, &heap).err().map(|error| error.kind)
+    }
+
+    #[test]
+    fn compiled_code_is_valid() {
+        let mut heap = init_heap();
+        let source = "class A { init(x) { this.x = x; } }
+                      fun f(n) { var a = A(n); fun g() { return a.x; } return g; }
+                      for (var i = 0; i < 3; i = i + 1) { if (i > 1) print f(i)(); }";
+        let function = compile(source, &mut heap, &|_| {}).expect("Compile error.");
+
+        assert_eq!(verify(function, &heap), Ok(()));
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        assert_eq!(
+            error_kind(&[200]),
+            Some(VerifyErrorKind::UnknownOpcode(200))
+        );
+    }
+
+    #[test]
+    fn jump_into_operands() {
+        let code = [
+            OpCode::OP_JUMP as u8,
+            0,
+            1,
+            OpCode::OP_GET_LOCAL as u8,
+            0,
+            OpCode::OP_RETURN as u8,
+        ];
+
+        assert_eq!(
+            error_kind(&code),
+            Some(VerifyErrorKind::JumpIntoInstruction(4))
+        );
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let code = [OpCode::OP_ADD as u8, OpCode::OP_RETURN as u8];
+
+        assert_eq!(error_kind(&code), Some(VerifyErrorKind::StackUnderflow));
+    }
+
+    #[test]
+    fn falls_off_end() {
+        assert_eq!(
+            error_kind(&[OpCode::OP_NIL as u8]),
+            Some(VerifyErrorKind::FallsOffEnd)
+        );
+    }
+
+    #[test]
+    fn script_with_parameters() {
+        let mut heap = init_heap();
+        let mut script = function(&mut heap, &[OpCode::OP_NIL as u8, OpCode::OP_RETURN as u8]);
+        script.arity = 1;
+        let script = heap.allocate(Obj::Function(script));
+
+        let error = verify(script, &heap).unwrap_err();
+        assert_eq!(error.kind, VerifyErrorKind::ScriptArity(1));
+    }
+
+    #[test]
+    fn script_with_upvalues() {
+        let mut heap = init_heap();
+        let code = [OpCode::OP_GET_UPVALUE as u8, 0, OpCode::OP_RETURN as u8];
+        let mut script = function(&mut heap, &code);
+        script.upvalue_count = 1;
+        let script = heap.allocate(Obj::Function(script));
+
+        let error = verify(script, &heap).unwrap_err();
+        assert_eq!(error.kind, VerifyErrorKind::ScriptUpvalues(1));
+    }
+
+    #[test]
+    fn return_pops_slot_zero() {
+        assert_eq!(
+            error_kind(&[OpCode::OP_RETURN as u8]),
+            Some(VerifyErrorKind::StackUnderflow)
+        );
+    }
+
+    // Every function returns nil and has the next one as a constant.
+    fn nested_functions(heap: &mut Heap, depth: usize) -> ObjRef {
+        let code = [OpCode::OP_NIL as u8, OpCode::OP_RETURN as u8];
+        let innermost = function(heap, &code);
+        let mut inner = heap.allocate(Obj::Function(innermost));
+        for _ in 1..depth {
+            let mut enclosing = function(heap, &code);
+            add_constant(&mut enclosing.chunk, Value::Obj(inner));
+            inner = heap.allocate(Obj::Function(enclosing));
+        }
+        inner
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit() {
+        let mut heap = init_heap();
+        let script = nested_functions(&mut heap, FUNCTION_NESTING_MAX);
+
+        assert_eq!(verify(script, &heap), Ok(()));
+    }
+
+    // Deep enough to overflow the native stack if it were walked
+    // recursively.
+    #[test]
+    fn deeply_nested_functions() {
+        let mut heap = init_heap();
+        let script = nested_functions(&mut heap, 20_000);
+
+        let error = verify(script, &heap).unwrap_err();
+        assert_eq!(error.kind, VerifyErrorKind::NestingTooDeep);
+    }
+
+    #[test]
+    fn deeper_than_vm_stack() {
+        let mut code = vec![OpCode::OP_NIL as u8; STACK_MAX];
+        code.push(OpCode::OP_RETURN as u8);
+
+        assert_eq!(
+            error_kind(&code),
+            Some(VerifyErrorKind::StackOverflow(STACK_MAX + 1))
+        );
+    }
+
+    // The verifier doesn't know the type of the values on the stack, the
+    // VM checks them instead of panicking.
+    #[test]
+    fn wrong_types_are_runtime_errors() {
+        let nil = OpCode::OP_NIL as u8;
+        let codes = [
+            vec![nil, nil, OpCode::OP_GET_SUPER as u8, 0],
+            vec![nil, nil, OpCode::OP_SUPER_INVOKE as u8, 0, 0],
+            vec![nil, nil, OpCode::OP_METHOD as u8, 0],
+            vec![OpCode::OP_CLASS as u8, 0, nil, OpCode::OP_METHOD as u8, 0],
+            vec![OpCode::OP_CLASS as u8, 0, nil, OpCode::OP_INHERIT as u8],
+        ];
+
+        for code in codes.iter() {
+            let mut code = code.clone();
+            code.push(OpCode::OP_RETURN as u8);
+            let mut heap = init_heap();
+            let script = script(&mut heap, &code);
+            assert_eq!(verify(script, &heap), Ok(()));
+
+            let mut vm = init_vm();
+            let result = vm.interpret_bytecode(&serialize(script, &heap));
+            assert!(matches!(result, InterpretResult::InterpretRuntimeError));
+        }
+    }
+}
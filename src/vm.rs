@@ -1,11 +1,20 @@
 use crate::chunk::{Chunk, OpCode};
 use crate::compiler;
 use crate::debug;
-use crate::value::Value;
+use crate::memory::{init_heap, Heap, ObjRef};
+use crate::serialize;
+use crate::span::print_span;
+use crate::table::{init_table, Table};
+use crate::value::{
+    values_equal, NativeFn, Obj, ObjBoundMethod, ObjClass, ObjClosure, ObjInstance, ObjNative,
+    ObjUpvalue, Value,
+};
+use crate::verify;
 
-const STACK_MAX: usize = 256;
+const FRAMES_MAX: usize = 64;
+pub const STACK_MAX: usize = FRAMES_MAX * (u8::MAX as usize + 1);
 
-#[allow(dead_code)]
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum InterpretResult {
     InterpretOk,
@@ -13,62 +22,186 @@ pub enum InterpretResult {
     InterpretRuntimeError,
 }
 
-// The C version of the VM is a global static variable,
-// but static mut variables in Rust may lead to unsafe
-// code, so a heap/stack allocated version is prefered
-// instead.
-pub struct VM {
-    chunk: Option<Chunk>,
+// A single ongoing function call.
+struct CallFrame {
+    closure: ObjRef,
     // The C version has an instruction pointer (ip) that
     // points directly to some position in the chunk for
     // performance reasons.
     // Here and offset is prefered because deref raw pointer
     // in Rust is not a safe operation. The Chunk has a
     // complementary get method that receives the offset.
-    offset: usize,
+    ip: usize,
+    // Index of the first VM stack slot the function can use, where the
+    // called function itself is stored.
+    slots: usize,
+}
+
+// The C version of the VM is a global static variable,
+// but static mut variables in Rust may lead to unsafe
+// code, so a heap/stack allocated version is prefered
+// instead.
+pub struct VM {
+    // The innermost call is the last frame.
+    frames: Vec<CallFrame>,
 
     stack: [Value; STACK_MAX],
     // Since the stack grows and shrinks as values are pushed and popped,
     // we need to track where the top of the stack is in the array.
     // stack_top points to where the next value to be pushed will go.
     stack_top: usize,
+
+    // Every object allocated by the compiler or at runtime.
+    heap: Heap,
+    // Global variables, they outlive a single interpret() call so the REPL
+    // remembers them from one line to the next.
+    globals: Table,
+    // Upvalues still pointing to a variable on the stack, sorted by stack
+    // slot, so several closures capturing the same variable share it.
+    open_upvalues: Vec<ObjRef>,
+    // Looked up on every class call, so it is interned only once.
+    init_string: ObjRef,
 }
 
 impl VM {
     fn new() -> VM {
-        VM {
-            chunk: None,
-            offset: 0,
-            stack: [0 as Value; STACK_MAX],
+        let mut heap = init_heap();
+        let init_string = heap.copy_string("init");
+
+        let mut vm = VM {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: [Value::Nil; STACK_MAX],
             stack_top: 0,
-        }
+            heap,
+            globals: init_table(),
+            open_upvalues: vec![],
+            init_string,
+        };
+
+        vm.define_native("clock", 0, clock_native);
+
+        vm
+    }
+
+    // Makes a Rust function available to Lox code as a global variable.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        // Both objects are kept on the stack until they are stored in the
+        // globals table, so they stay reachable.
+        let name = self.heap.copy_string(name);
+        self.push(Value::Obj(name));
+        let native = self
+            .heap
+            .allocate(Obj::Native(ObjNative { arity, function }));
+        self.push(Value::Obj(native));
+
+        let hash = self.heap.as_string(name).hash;
+        self.globals.set(name, hash, self.peek(0));
+
+        self.pop();
+        self.pop();
     }
 
     pub fn interpret(&mut self, source: &str) -> InterpretResult {
-        compiler::compile(source);
-        return InterpretResult::InterpretOk;
+        let function = match self.compile(source) {
+            Some(function) => function,
+            None => return InterpretResult::InterpretCompileError,
+        };
+
+        self.run_script(function)
+    }
+
+    // Runs a script compiled ahead of time in the .loxc format. A file
+    // that can't be loaded is reported like a compile error.
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> InterpretResult {
+        let function = match serialize::deserialize(bytes, &mut self.heap) {
+            Ok(function) => function,
+            Err(message) => {
+                eprintln!("Can't load bytecode: {}", message);
+                return InterpretResult::InterpretCompileError;
+            }
+        };
+
+        // The file can be well formed and still have code the VM would
+        // choke on, so it is checked before running any of it.
+        if let Err(error) = verify::verify(function, &self.heap) {
+            eprintln!("Can't load bytecode: {}", error);
+            return InterpretResult::InterpretCompileError;
+        }
+
+        self.run_script(function)
+    }
+
+    // Compiles the script into the .loxc format without running it.
+    pub fn compile_bytecode(&mut self, source: &str) -> Option<Vec<u8>> {
+        let function = self.compile(source)?;
+        Some(serialize::serialize(function, &self.heap))
+    }
+
+    fn compile(&mut self, source: &str) -> Option<ObjRef> {
+        // The stack is empty between two interpret() calls, but the globals
+        // are still alive.
+        let globals = &self.globals;
+        let init_string = self.init_string;
+        let mark_vm_roots = |heap: &mut Heap| {
+            globals.mark(heap);
+            heap.mark_object(init_string);
+        };
+
+        compiler::compile(source, &mut self.heap, &mark_vm_roots)
+    }
+
+    fn run_script(&mut self, function: ObjRef) -> InterpretResult {
+        // Each interpretation starts with an empty stack, even when the VM
+        // is reused by the REPL.
+        self.reset_stack();
+
+        // The top-level script is called like any other function.
+        self.push(Value::Obj(function));
+        let closure = self.heap.allocate(Obj::Closure(ObjClosure {
+            function,
+            upvalues: vec![],
+        }));
+        self.pop();
+        self.push(Value::Obj(closure));
+        // Only a script loaded from a file can fail here, the compiler
+        // never gives it parameters.
+        if !self.call(closure, 0) {
+            return InterpretResult::InterpretRuntimeError;
+        }
+
+        self.run()
     }
 
     fn run(&mut self) -> InterpretResult {
         // Each turn through loop, read and execute a single bytecode instruction.
         loop {
+            // Between two instructions every live object is reachable from
+            // the roots, anything still in use is on the stack.
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+
+            // No instruction leaves more than one new value on the stack,
+            // so checking before each one keeps push() inside the array.
+            if self.stack_top == STACK_MAX {
+                self.stack_overflow_error();
+                return InterpretResult::InterpretRuntimeError;
+            }
+
             #[cfg(feature = "debug-trace-execution")]
             {
                 print!("          ");
                 for i in 0..self.stack_top {
                     print!("[ ");
-                    debug::print_value(self.stack[i]);
+                    debug::print_value(self.stack[i], &self.heap);
                     print!(" ]");
                 }
                 println!();
-                debug::dissassemble_instruction(
-                    &self.chunk.as_ref().expect("Error getting chunk reference"),
-                    self.offset,
-                );
+                debug::dissassemble_instruction(self.chunk(), self.frame().ip, &self.heap);
             }
 
             // The first byte of any instruction is the opcode.
-            let opcode = self.read_byte();
+            let opcode = self.read_opcode();
 
             // Given a numeric opcode, we need to get to the right Rust code that
             // implements that instruction’s semantics. This process is called
@@ -78,59 +211,678 @@ impl VM {
                     let constant = self.read_constant();
                     self.push(constant);
                 }
+                OpCode::OP_CONSTANT_LONG => {
+                    let constant = self.read_constant_long();
+                    self.push(constant);
+                }
+                OpCode::OP_NIL => self.push(Value::Nil),
+                OpCode::OP_TRUE => self.push(Value::Bool(true)),
+                OpCode::OP_FALSE => self.push(Value::Bool(false)),
+                OpCode::OP_POP => {
+                    self.pop();
+                }
+                OpCode::OP_GET_LOCAL => {
+                    let slot = self.read_byte() as usize;
+                    self.push(self.stack[self.frame().slots + slot]);
+                }
+                OpCode::OP_SET_LOCAL => {
+                    // Assignment is an expression, so the value is left on
+                    // the stack.
+                    let slot = self.read_byte() as usize;
+                    self.stack[self.frame().slots + slot] = self.peek(0);
+                }
+                OpCode::OP_GET_GLOBAL | OpCode::OP_GET_GLOBAL_LONG => {
+                    let name = self.read_string(opcode);
+                    let hash = self.heap.as_string(name).hash;
+                    match self.globals.get(name, hash) {
+                        Some(value) => self.push(value),
+                        None => {
+                            self.undefined_variable(name);
+                            return InterpretResult::InterpretRuntimeError;
+                        }
+                    }
+                }
+                OpCode::OP_DEFINE_GLOBAL | OpCode::OP_DEFINE_GLOBAL_LONG => {
+                    let name = self.read_string(opcode);
+                    let hash = self.heap.as_string(name).hash;
+                    // The value is popped after adding it to the table, so it
+                    // is still reachable while the table grows.
+                    let value = self.peek(0);
+                    self.globals.set(name, hash, value);
+                    self.pop();
+                }
+                OpCode::OP_SET_GLOBAL | OpCode::OP_SET_GLOBAL_LONG => {
+                    let name = self.read_string(opcode);
+                    let hash = self.heap.as_string(name).hash;
+                    let value = self.peek(0);
+                    // Assignment doesn't define variables, so undo the set
+                    // if the variable didn't exist.
+                    if self.globals.set(name, hash, value) {
+                        self.globals.delete(name, hash);
+                        self.undefined_variable(name);
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                }
+                OpCode::OP_GET_UPVALUE => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.heap.as_closure(self.frame().closure).upvalues[slot];
+                    let value = match self.heap.as_upvalue(upvalue) {
+                        ObjUpvalue {
+                            closed: Some(value),
+                            ..
+                        } => *value,
+                        ObjUpvalue { location, .. } => self.stack[*location],
+                    };
+                    self.push(value);
+                }
+                OpCode::OP_SET_UPVALUE => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.heap.as_closure(self.frame().closure).upvalues[slot];
+                    let value = self.peek(0);
+                    let upvalue = self.heap.as_upvalue_mut(upvalue);
+                    match upvalue.closed {
+                        Some(_) => upvalue.closed = Some(value),
+                        None => self.stack[upvalue.location] = value,
+                    }
+                }
+                OpCode::OP_GET_PROPERTY | OpCode::OP_GET_PROPERTY_LONG => {
+                    if !self.heap.is_instance(self.peek(0)) {
+                        self.runtime_error("Only instances have properties.");
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+
+                    let instance = self.peek(0).as_obj();
+                    let name = self.read_string(opcode);
+                    let hash = self.heap.as_string(name).hash;
+                    // Fields shadow methods with the same name.
+                    if let Some(value) = self.heap.as_instance(instance).fields.get(name, hash) {
+                        self.pop(); // Instance.
+                        self.push(value);
+                        continue;
+                    }
+
+                    let class = self.heap.as_instance(instance).class;
+                    if !self.bind_method(class, name) {
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                }
+                OpCode::OP_SET_PROPERTY | OpCode::OP_SET_PROPERTY_LONG => {
+                    if !self.heap.is_instance(self.peek(1)) {
+                        self.runtime_error("Only instances have fields.");
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+
+                    let instance = self.peek(1).as_obj();
+                    let name = self.read_string(opcode);
+                    let hash = self.heap.as_string(name).hash;
+                    let value = self.peek(0);
+                    self.heap.set_field(instance, name, hash, value);
+
+                    // The assignment is an expression, its value replaces
+                    // the instance on the stack.
+                    let value = self.pop();
+                    self.pop();
+                    self.push(value);
+                }
+                OpCode::OP_GET_SUPER | OpCode::OP_GET_SUPER_LONG => {
+                    // The compiler only puts a class in `super`, but
+                    // bytecode loaded from a file could have anything.
+                    if !self.heap.is_class(self.peek(0)) {
+                        self.runtime_error("Superclass must be a class.");
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+
+                    let name = self.read_string(opcode);
+                    let superclass = self.pop().as_obj();
+
+                    if !self.bind_method(superclass, name) {
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                }
+                OpCode::OP_EQUAL => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    self.push(Value::Bool(values_equal(a, b)));
+                }
+                OpCode::OP_GREATER => {
+                    if !self.binary_operator(|a, b| Value::Bool(a > b)) {
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                }
+                OpCode::OP_LESS => {
+                    if !self.binary_operator(|a, b| Value::Bool(a < b)) {
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                }
                 OpCode::OP_ADD => {
-                    self.binary_operator(|a, b| a + b);
+                    if self.heap.is_string(self.peek(0)) && self.heap.is_string(self.peek(1)) {
+                        self.concatenate();
+                    } else if self.peek(0).is_number() && self.peek(1).is_number() {
+                        self.binary_operator(|a, b| Value::Number(a + b));
+                    } else {
+                        self.runtime_error("Operands must be two numbers or two strings.");
+                        return InterpretResult::InterpretRuntimeError;
+                    }
                 }
                 OpCode::OP_SUBSTRACT => {
-                    self.binary_operator(|a, b| a - b);
+                    if !self.binary_operator(|a, b| Value::Number(a - b)) {
+                        return InterpretResult::InterpretRuntimeError;
+                    }
                 }
                 OpCode::OP_MULTIPLY => {
-                    self.binary_operator(|a, b| a * b);
+                    if !self.binary_operator(|a, b| Value::Number(a * b)) {
+                        return InterpretResult::InterpretRuntimeError;
+                    }
                 }
                 OpCode::OP_DIVIDE => {
-                    self.binary_operator(|a, b| a / b);
+                    if !self.binary_operator(|a, b| Value::Number(a / b)) {
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                }
+                OpCode::OP_NOT => {
+                    let value = self.pop();
+                    self.push(Value::Bool(value.is_falsey()));
                 }
                 OpCode::OP_NEGATE => {
+                    if !self.peek(0).is_number() {
+                        self.runtime_error("Operand must be a number.");
+                        return InterpretResult::InterpretRuntimeError;
+                    }
                     let value = self.pop();
-                    self.push(-value);
+                    self.push(Value::Number(-value.as_number()));
                 }
-                OpCode::OP_RETURN => {
-                    debug::print_value(self.pop());
+                OpCode::OP_PRINT => {
+                    debug::print_value(self.pop(), &self.heap);
                     println!();
-                    return InterpretResult::InterpretOk;
+                }
+                OpCode::OP_JUMP => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                }
+                OpCode::OP_JUMP_IF_FALSE => {
+                    // The condition is left on the stack, the compiler
+                    // emits the pops.
+                    let offset = self.read_short();
+                    if self.peek(0).is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::OP_LOOP => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                }
+                OpCode::OP_CALL => {
+                    let arg_count = self.read_byte() as usize;
+                    if !self.call_value(self.peek(arg_count), arg_count) {
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                }
+                OpCode::OP_INVOKE | OpCode::OP_INVOKE_LONG => {
+                    let method = self.read_string(opcode);
+                    let arg_count = self.read_byte() as usize;
+                    if !self.invoke(method, arg_count) {
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                }
+                OpCode::OP_SUPER_INVOKE | OpCode::OP_SUPER_INVOKE_LONG => {
+                    if !self.heap.is_class(self.peek(0)) {
+                        self.runtime_error("Superclass must be a class.");
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+
+                    let method = self.read_string(opcode);
+                    let arg_count = self.read_byte() as usize;
+                    let superclass = self.pop().as_obj();
+                    if !self.invoke_from_class(superclass, method, arg_count) {
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                }
+                OpCode::OP_CLOSURE | OpCode::OP_CLOSURE_LONG => {
+                    let function = self.read_constant_of(opcode).as_obj();
+                    let upvalue_count = self.heap.as_function(function).upvalue_count;
+
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        if is_local {
+                            let location = self.frame().slots + index;
+                            upvalues.push(self.capture_upvalue(location));
+                        } else {
+                            let enclosing = self.frame().closure;
+                            upvalues.push(self.heap.as_closure(enclosing).upvalues[index]);
+                        }
+                    }
+
+                    let closure = self
+                        .heap
+                        .allocate(Obj::Closure(ObjClosure { function, upvalues }));
+                    self.push(Value::Obj(closure));
+                }
+                OpCode::OP_CLOSE_UPVALUE => {
+                    // The local on top of the stack is going out of scope.
+                    self.close_upvalues(self.stack_top - 1);
+                    self.pop();
+                }
+                OpCode::OP_RETURN => {
+                    let result = self.pop();
+                    // Parameters and locals of the returning function are
+                    // going away, the closures capturing them keep a copy.
+                    self.close_upvalues(self.frame().slots);
+                    let frame = self.frames.pop().expect("No frame to return from.");
+                    if self.frames.is_empty() {
+                        // Pop the top-level script and exit interpreter.
+                        self.pop();
+                        return InterpretResult::InterpretOk;
+                    }
+
+                    // Discard the callee and its arguments and locals.
+                    self.stack_top = frame.slots;
+                    self.push(result);
+                }
+                OpCode::OP_CLASS | OpCode::OP_CLASS_LONG => {
+                    let name = self.read_string(opcode);
+                    let class = self.heap.allocate(Obj::Class(ObjClass {
+                        name,
+                        methods: init_table(),
+                    }));
+                    self.push(Value::Obj(class));
+                }
+                OpCode::OP_INHERIT => {
+                    let superclass = self.peek(1);
+                    if !self.heap.is_class(superclass) {
+                        self.runtime_error("Superclass must be a class.");
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                    if !self.heap.is_class(self.peek(0)) {
+                        self.runtime_error("Only classes can inherit.");
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+
+                    // Copy-down inheritance, the methods of the superclass
+                    // are copied into the subclass before it defines its
+                    // own, which override them.
+                    let subclass = self.peek(0).as_obj();
+                    self.heap.inherit(subclass, superclass.as_obj());
+                    self.pop(); // Subclass.
+                }
+                OpCode::OP_METHOD | OpCode::OP_METHOD_LONG => {
+                    let name = self.read_string(opcode);
+                    if !self.define_method(name) {
+                        return InterpretResult::InterpretRuntimeError;
+                    }
                 }
             }
         }
     }
 
-    fn read_byte(&mut self) -> OpCode {
-        let opcode = self
-            .chunk
-            .as_ref()
-            .expect("The chunk is not present.")
-            .get(self.offset);
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("There is no call frame.")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("There is no call frame.")
+    }
+
+    // The chunk of the function being executed.
+    fn chunk(&self) -> &Chunk {
+        let function = self.heap.as_closure(self.frame().closure).function;
+        &self.heap.as_function(function).chunk
+    }
+
+    fn read_opcode(&mut self) -> OpCode {
+        let opcode = self.chunk().get(self.frame().ip);
 
         // The opcodes are 1 byte lenght, so...
-        self.offset += 1;
+        self.frame_mut().ip += 1;
 
         opcode
     }
 
-    fn read_constant(&mut self) -> Value {
-        let chunk = self.chunk.as_ref().expect("the chunk is not present.");
-        let index = chunk.get(self.offset) as usize;
+    fn read_byte(&mut self) -> u8 {
+        let byte = self.chunk().get_byte(self.frame().ip);
+        self.frame_mut().ip += 1;
+
+        byte
+    }
+
+    // Jump offsets are 16-bit operands stored big-endian.
+    fn read_short(&mut self) -> u16 {
+        let high = self.read_byte() as u16;
+        let low = self.read_byte() as u16;
+
+        high << 8 | low
+    }
 
+    fn read_constant(&mut self) -> Value {
         // The constants index are 1 byte lenght, so...
-        self.offset += 1;
+        let index = self.read_byte() as usize;
 
-        chunk.get_constant_value(index)
+        self.chunk().get_constant_value(index)
     }
 
-    fn binary_operator<F: Fn(Value, Value) -> Value>(&mut self, f: F) {
-        let b = self.pop();
-        let a = self.pop();
+    fn read_constant_long(&mut self) -> Value {
+        let high = self.read_byte() as usize;
+        let middle = self.read_byte() as usize;
+        let low = self.read_byte() as usize;
+
+        self.chunk()
+            .get_constant_value(high << 16 | middle << 8 | low)
+    }
+
+    // The constant operand of an instruction, one byte long or three in
+    // the long form.
+    fn read_constant_of(&mut self, opcode: OpCode) -> Value {
+        if opcode.is_long() {
+            self.read_constant_long()
+        } else {
+            self.read_constant()
+        }
+    }
+
+    fn read_string(&mut self, opcode: OpCode) -> ObjRef {
+        self.read_constant_of(opcode).as_obj()
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> bool {
+        let function = self.heap.as_closure(closure).function;
+        let arity = self.heap.as_function(function).arity;
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            self.runtime_error(&message);
+            return false;
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            self.runtime_error("Stack overflow.");
+            return false;
+        }
+
+        // The callee and its arguments are already on the stack, they
+        // become the first slots of the new frame.
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots: self.stack_top - arg_count - 1,
+        });
+        true
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> bool {
+        if let Value::Obj(obj) = callee {
+            match self.heap.get(obj) {
+                Obj::BoundMethod(bound_method) => {
+                    // The receiver goes in slot zero, where the method
+                    // expects `this`.
+                    let (receiver, method) = (bound_method.receiver, bound_method.method);
+                    self.stack[self.stack_top - arg_count - 1] = receiver;
+                    return self.call(method, arg_count);
+                }
+                Obj::Class(class) => {
+                    let hash = self.heap.as_string(self.init_string).hash;
+                    let initializer = class.methods.get(self.init_string, hash);
+
+                    // The instance takes the place of the class on the
+                    // stack.
+                    let instance = self.heap.allocate(Obj::Instance(ObjInstance {
+                        class: obj,
+                        fields: init_table(),
+                    }));
+                    self.stack[self.stack_top - arg_count - 1] = Value::Obj(instance);
+
+                    match initializer {
+                        Some(initializer) => return self.call(initializer.as_obj(), arg_count),
+                        None if arg_count != 0 => {
+                            let message = format!("Expected 0 arguments but got {}.", arg_count);
+                            self.runtime_error(&message);
+                            return false;
+                        }
+                        None => return true,
+                    }
+                }
+                Obj::Closure(_) => return self.call(obj, arg_count),
+                Obj::Native(native) => {
+                    let (arity, function) = (native.arity, native.function);
+                    return self.call_native(function, arity, arg_count);
+                }
+                _ => (), // Non-callable object type.
+            }
+        }
+
+        self.runtime_error("Can only call functions and classes.");
+        false
+    }
+
+    // Calls a method without creating a bound method first, the receiver is
+    // already in the slot below the arguments.
+    fn invoke(&mut self, name: ObjRef, arg_count: usize) -> bool {
+        let receiver = self.peek(arg_count);
+        if !self.heap.is_instance(receiver) {
+            self.runtime_error("Only instances have methods.");
+            return false;
+        }
+
+        // A field can hold a function, it is called like any other value.
+        let instance = self.heap.as_instance(receiver.as_obj());
+        let hash = self.heap.as_string(name).hash;
+        if let Some(value) = instance.fields.get(name, hash) {
+            self.stack[self.stack_top - arg_count - 1] = value;
+            return self.call_value(value, arg_count);
+        }
+
+        let class = instance.class;
+        self.invoke_from_class(class, name, arg_count)
+    }
+
+    fn invoke_from_class(&mut self, class: ObjRef, name: ObjRef, arg_count: usize) -> bool {
+        let hash = self.heap.as_string(name).hash;
+        match self.heap.as_class(class).methods.get(name, hash) {
+            Some(method) => self.call(method.as_obj(), arg_count),
+            None => {
+                let message = format!("Undefined property '{}'.", self.heap.as_string(name).chars);
+                self.runtime_error(&message);
+                false
+            }
+        }
+    }
+
+    // Replaces the instance on top of the stack with the method bound to
+    // it.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> bool {
+        let hash = self.heap.as_string(name).hash;
+        let method = match self.heap.as_class(class).methods.get(name, hash) {
+            Some(method) => method.as_obj(),
+            None => {
+                let message = format!("Undefined property '{}'.", self.heap.as_string(name).chars);
+                self.runtime_error(&message);
+                return false;
+            }
+        };
+
+        let bound = self.heap.allocate(Obj::BoundMethod(ObjBoundMethod {
+            receiver: self.peek(0),
+            method,
+        }));
+        self.pop();
+        self.push(Value::Obj(bound));
+        true
+    }
+
+    // The method closure is on top of the stack and its class right below.
+    // Calls look methods up expecting a closure, so anything else is
+    // rejected here instead of when it is called.
+    fn define_method(&mut self, name: ObjRef) -> bool {
+        if !self.heap.is_class(self.peek(1)) {
+            self.runtime_error("Only classes have methods.");
+            return false;
+        }
+        if !self.heap.is_closure(self.peek(0)) {
+            self.runtime_error("Methods must be functions.");
+            return false;
+        }
+
+        let method = self.peek(0);
+        let class = self.peek(1).as_obj();
+        let hash = self.heap.as_string(name).hash;
+        self.heap.set_method(class, name, hash, method);
+        self.pop();
+        true
+    }
+
+    // Returns the open upvalue for the stack slot, creating it if no
+    // closure captured the variable before.
+    fn capture_upvalue(&mut self, location: usize) -> ObjRef {
+        let position = self
+            .open_upvalues
+            .iter()
+            .rposition(|upvalue| self.heap.as_upvalue(*upvalue).location <= location);
+
+        if let Some(position) = position {
+            let upvalue = self.open_upvalues[position];
+            if self.heap.as_upvalue(upvalue).location == location {
+                return upvalue;
+            }
+        }
+
+        let created = self.heap.allocate(Obj::Upvalue(ObjUpvalue {
+            location,
+            closed: None,
+        }));
+        let index = position.map_or(0, |position| position + 1);
+        self.open_upvalues.insert(index, created);
+
+        created
+    }
+
+    // Closes every open upvalue pointing to the given stack slot or any
+    // slot above it, moving the values off the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let upvalue = self.heap.as_upvalue_mut(*upvalue);
+            if upvalue.location < last {
+                break;
+            }
+
+            upvalue.closed = Some(self.stack[upvalue.location]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    // Natives run right away, there is no call frame for them.
+    fn call_native(&mut self, function: NativeFn, arity: usize, arg_count: usize) -> bool {
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            self.runtime_error(&message);
+            return false;
+        }
+
+        let args = &self.stack[self.stack_top - arg_count..self.stack_top];
+        match function(args) {
+            Ok(result) => {
+                // Discard the native and its arguments.
+                self.stack_top -= arg_count + 1;
+                self.push(result);
+                true
+            }
+            Err(message) => {
+                self.runtime_error(&message);
+                false
+            }
+        }
+    }
+
+    // Returns false after reporting a runtime error if any of the operands
+    // is not a number.
+    fn binary_operator<F: Fn(f64, f64) -> Value>(&mut self, f: F) -> bool {
+        if !self.peek(0).is_number() || !self.peek(1).is_number() {
+            self.runtime_error("Operands must be numbers.");
+            return false;
+        }
+
+        let b = self.pop().as_number();
+        let a = self.pop().as_number();
         let r = f(a, b);
         self.push(r);
+
+        true
+    }
+
+    fn concatenate(&mut self) {
+        let b = self.pop().as_obj();
+        let a = self.pop().as_obj();
+
+        let mut chars = self.heap.as_string(a).chars.clone();
+        chars.push_str(&self.heap.as_string(b).chars);
+
+        let result = self.heap.take_string(chars);
+        self.push(Value::Obj(result));
+    }
+
+    fn collect_garbage(&mut self) {
+        let stack = &self.stack[..self.stack_top];
+        let frames = &self.frames;
+        let globals = &self.globals;
+        let open_upvalues = &self.open_upvalues;
+        let init_string = self.init_string;
+
+        self.heap.collect_garbage(|heap| {
+            for value in stack {
+                heap.mark_value(*value);
+            }
+            for frame in frames {
+                heap.mark_object(frame.closure);
+            }
+            for upvalue in open_upvalues {
+                heap.mark_object(*upvalue);
+            }
+            globals.mark(heap);
+            heap.mark_object(init_string);
+        });
+    }
+
+    fn reset_stack(&mut self) {
+        self.stack_top = 0;
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    fn runtime_error(&mut self, message: &str) {
+        eprintln!("{}", message);
+
+        // Point at the code of the instruction that failed.
+        if let Some(frame) = self.frames.last() {
+            let chunk = self.chunk();
+            if let (Some(source), Some(span)) = (chunk.source(), chunk.get_span(frame.ip - 1)) {
+                print_span(source, span);
+            }
+        }
+
+        self.print_stack_trace();
+        self.reset_stack();
+    }
+
+    // The values on the stack belong to every call still running, no
+    // single instruction is at fault, so there is no code to point at.
+    fn stack_overflow_error(&mut self) {
+        eprintln!("Stack overflow.");
+        self.print_stack_trace();
+        self.reset_stack();
+    }
+
+    fn print_stack_trace(&self) {
+        // Print a stack trace from the innermost call, where the error
+        // happened, to the top-level script.
+        for frame in self.frames.iter().rev() {
+            let closure = self.heap.as_closure(frame.closure);
+            let function = self.heap.as_function(closure.function);
+            // The ip is already past the faulting instruction.
+            let instruction = frame.ip - 1;
+            let line = function.chunk.get_line(instruction);
+            match function.name {
+                Some(name) => eprintln!("[line {}] in {}()", line, self.heap.as_string(name).chars),
+                None => eprintln!("[line {}] in script", line),
+            }
+        }
     }
 
     fn push(&mut self, value: Value) {
@@ -138,6 +890,17 @@ impl VM {
         self.stack_top += 1;
     }
 
+    fn undefined_variable(&mut self, name: ObjRef) {
+        let message = format!("Undefined variable '{}'.", self.heap.as_string(name).chars);
+        self.runtime_error(&message);
+    }
+
+    // Returns a value from the stack without popping it, distance is how
+    // far down from the top of the stack to look.
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack_top - 1 - distance]
+    }
+
     fn pop(&mut self) -> Value {
         // We don’t need to explicitly “remove” it from the array—moving stackTop
         // down is enough to mark that slot as no longer in use.
@@ -146,6 +909,14 @@ impl VM {
     }
 }
 
+// Seconds since the Unix epoch, only useful to measure elapsed time.
+fn clock_native(_args: &[Value]) -> Result<Value, String> {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| Value::Number(elapsed.as_secs_f64()))
+        .map_err(|error| error.to_string())
+}
+
 pub fn init_vm() -> VM {
-    return VM::new();
+    VM::new()
 }